@@ -1,11 +1,6 @@
-use hex;
-use std::collections::HashSet;
-use std::ops::ControlFlow;
-use std::{env, net, thread};
-mod error;
+use std::path::PathBuf;
+use std::{net, thread};
 use arboard::Clipboard;
-use nakamoto_cash::chain::Transaction;
-use nakamoto_cash::common::bitcoin::{Txid, cash_addr};
 use slint::PlatformError;
 use slint::{Model, ModelRc, SharedString};
 use std::rc::Rc;
@@ -13,301 +8,64 @@ use std::rc::Rc;
 slint::include_modules!();
 
 use argh::FromArgs;
-use client::{Client, Config, Event};
-mod logger;
+use client::Client;
 use nakamoto_cash::client::traits::Handle;
 use nakamoto_cash::client::{self, Network};
-use nakamoto_cash::common::bitcoin::util::bloom::{Bloom, BloomFilter};
-use nakamoto_cash::p2p::Command;
-use nakamoto_cash::p2p::PeerId;
+use p2p_client::config::{Overrides, Settings};
+use p2p_client::logger;
+use p2p_client::{Error, MerkleScanRange, Shutdown, UIMessage, WatchList, Watcher};
 type Reactor = nakamoto_cash::net::poll::Reactor<net::TcpStream>;
 use crossbeam_channel::{self as chan, Receiver, Sender};
-const TXID_LEN: usize = 32;
-
-#[derive(Clone, Debug)]
-pub struct MerkleScanRange {
-    begin: u64,
-    end: u64,
-}
-
-impl MerkleScanRange {
-    pub fn new(begin: u64, end: u64) -> Self {
-        Self { begin, end }
-    }
-}
-
-#[derive(Clone, Debug)]
-pub enum UIMessage {
-    NetworkConnected(Network),
-    BlocksDownloading(bool),
-    HeaderLoaded(u64),
-    BlockConnected(u64),
-    AddBloomItem(String),
-    SendLoadFilter,
-    ResetFilter,
-    ClearFilterAndPeers,
-    PeerLoadedFilter(PeerId),
-    ReceivedMatchedTx { transaction: Transaction },
-    ReceivedBlock(u64),
-    RequestBlocks(MerkleScanRange),
-}
-
-#[derive(Clone)]
-pub struct FilterState {
-    bloom: BloomFilter,
-    peers: Vec<PeerId>,
-    filtered_peers: Vec<(PeerId, bool)>,
-    is_set: bool,
-}
-
-impl FilterState {
-    pub fn new() -> Self {
-        let filter = Bloom::<u8>::new_for_fp_rate(10_000, 0.01);
-        Self {
-            bloom: BloomFilter::from(filter),
-            peers: Vec::with_capacity(32),
-            filtered_peers: Vec::with_capacity(32),
-            is_set: false,
-        }
-    }
-
-    pub fn reset(&mut self) {
-        let filter = Bloom::<u8>::new_for_fp_rate(10_000, 0.01);
-        self.bloom = BloomFilter::from(filter);
-        self.filtered_peers.clear();
-        self.is_set = false;
-        // You will get flooded with transactions, let's send an unset filter instead.
-        // _ = client.command(Command::BloomFilterClear);
-    }
-
-    pub fn add_bloom_item(&mut self, data: String) -> Result<(), error::Error> {
-        let mut temp_bloom = Bloom::<u8>::new_for_fp_rate(1024, 0.01);
-        if let Ok(addr_data) = cash_addr::decode(data.as_str()) {
-            temp_bloom.set(&mut addr_data.0.clone());
-        } else {
-            match hex::decode(data) {
-                Ok(mut bytes) => {
-                    if TXID_LEN == bytes.len() {
-                        bytes.reverse();
-                        log::info!("Loading txid {:?}", hex::encode(bytes.clone()));
-                    }
-                    temp_bloom.set(&mut bytes);
-                }
-                Err(e) => log::error!("Error decoding hex: {}", e),
-            }
-        }
-        self.bloom = BloomFilter::from(temp_bloom);
-        Ok(())
-    }
-
-    pub fn send_bloom_filter<H: Handle>(
-        &mut self,
-        client: &H,
-        peers: Vec<(PeerId, bool)>,
-    ) -> Result<(), error::Error> {
-        let filter = self.bloom.clone();
-        self.filtered_peers.extend(peers.clone());
-        let peers = peers.iter().map(|p| p.0).collect::<Vec<_>>();
-        client
-            .command(Command::LoadBloomFilter((filter, peers)))
-            .map_err(|e| error::Error::from(e))?;
-        Ok(())
-    }
-}
-
-pub struct Watcher<H> {
-    client: H,
-    network: Network,
-    filter_state: FilterState,
-    txids: HashSet<Txid>,
-}
-
-impl<H: Handle> Watcher<H> {
-    pub fn new(client: H, network: client::Network) -> Self {
-        Self {
-            client,
-            network,
-            filter_state: FilterState::new(),
-            txids: HashSet::new(),
-        }
-    }
-
-    pub fn run(
-        &mut self,
-        events: &Receiver<client::Event>,
-        ui_input_rx: &Receiver<UIMessage>,
-        ui_show_tx: &Sender<UIMessage>,
-    ) -> Result<(), error::Error> {
-        loop {
-            if let Ok(event) = ui_input_rx.try_recv() {
-                if let ControlFlow::Break(()) = self.handle_user_input(event)? {
-                    break;
-                }
-            }
-            if let Ok(event) = events.try_recv() {
-                if let ControlFlow::Break(()) = self.handle_client_event(event, ui_show_tx)? {
-                    break;
-                }
-            }
-        }
-        Ok(())
-    }
-
-    fn handle_client_event(
-        &mut self,
-        event: client::Event,
-        ui_show_tx: &chan::Sender<UIMessage>,
-    ) -> Result<ControlFlow<()>, error::Error> {
-        match event {
-            Event::Ready { tip, time, .. } => {
-                ui_show_tx.send(UIMessage::HeaderLoaded(tip)).unwrap();
-                ui_show_tx
-                    .send(UIMessage::NetworkConnected(self.network))
-                    .unwrap();
-                log::info!("Client Ready {:?}", time.to_string());
-            }
-            Event::BlockConnected { height, .. } => {
-                ui_show_tx.send(UIMessage::BlockConnected(height)).unwrap();
-            }
-            Event::PeerNegotiated { addr, .. } => {
-                self.filter_state.peers.push(addr);
-            }
-            Event::MerkleBlockScanStarted { peer, .. } => {
-                self.filter_state.filtered_peers.iter_mut().for_each(|p| {
-                    if p.0 == peer {
-                        p.1 = true;
-                    }
-                });
-                ui_show_tx.send(UIMessage::BlocksDownloading(true)).unwrap();
-            }
-            Event::MerkleBlockRescanStopped { peer, .. } => {
-                self.filter_state.filtered_peers.iter_mut().for_each(|p| {
-                    if p.0 == peer {
-                        p.1 = false;
-                    }
-                });
-                if self.filter_state.filtered_peers.iter().all(|p| !p.1) {
-                    ui_show_tx
-                        .send(UIMessage::BlocksDownloading(false))
-                        .unwrap();
-                }
-            }
-            Event::PeerDisconnected { addr, .. } => {
-                self.filter_state.peers.retain(|peer| peer != &addr);
-                self.filter_state
-                    .filtered_peers
-                    .retain(|peer| peer.0 != addr);
-            }
-            Event::ReceivedMatchedTx { transaction } => {
-                self.txids.insert(transaction.txid());
-                ui_show_tx
-                    .send(UIMessage::ReceivedMatchedTx { transaction })
-                    .unwrap();
-            }
-            Event::ReceivedMerkleBlock {
-                merkle_block,
-                height,
-                ..
-            } => {
-                _ = merkle_block;
-                ui_show_tx.send(UIMessage::ReceivedBlock(height)).unwrap();
-                // let mut matches = self.txids.iter().cloned().collect::<Vec<_>>();
-                // let mut indexes: Vec<u32> = vec![];
-                // _ = merkle_block.extract_matches(&mut matches, &mut indexes);
-            }
-            Event::PeerLoadedBloomFilter { peer, .. } => {
-                if self.filter_state.is_set {
-                    ui_show_tx.send(UIMessage::PeerLoadedFilter(peer)).unwrap();
-                }
-            }
-            _ => {}
-        }
-        Ok(ControlFlow::Continue(()))
-    }
-
-    fn handle_user_input(&mut self, ui_input: UIMessage) -> Result<ControlFlow<()>, error::Error> {
-        match ui_input {
-            UIMessage::AddBloomItem(data) => {
-                self.filter_state.add_bloom_item(data)?;
-                self.filter_state.is_set = true;
-            }
-            UIMessage::SendLoadFilter => {
-                if !self.filter_state.peers.is_empty() {
-                    let peer_ids = self
-                        .filter_state
-                        .peers
-                        .to_vec()
-                        .iter()
-                        .cloned()
-                        .map(|p| (p, false))
-                        .collect::<Vec<_>>();
-                    self.filter_state
-                        .send_bloom_filter(&self.client, peer_ids)?;
-                }
-            }
-            UIMessage::ResetFilter => {}
-            UIMessage::ClearFilterAndPeers => {
-                self.filter_state.reset();
-                self.filter_state
-                    .send_bloom_filter(
-                        &self.client,
-                        self.filter_state
-                            .peers
-                            .to_vec()
-                            .iter()
-                            .cloned()
-                            .map(|p| (p, false))
-                            .collect::<Vec<_>>(),
-                    )
-                    .unwrap();
-            }
-            UIMessage::RequestBlocks(range) => {
-                self.client.command(Command::MerkleBlockRescan {
-                    from: std::ops::Bound::Included(range.begin),
-                    to: std::ops::Bound::Included(range.end),
-                    peers: self
-                        .filter_state
-                        .filtered_peers
-                        .to_vec()
-                        .iter()
-                        .cloned()
-                        .map(|p| (p.0))
-                        .collect::<Vec<_>>(),
-                })?;
-            }
-            _ => {}
-        }
-        Ok(ControlFlow::Continue(()))
-    }
-}
 
 /// A Bitcoin P2P Light Client.
 #[derive(FromArgs)]
 pub struct Options {
+    /// configuration file, defaults to `p2p-client.toml` in the data directory
+    #[argh(option)]
+    pub config: Option<PathBuf>,
+    /// data directory, with one sub-directory per network
+    #[argh(option)]
+    pub data_dir: Option<PathBuf>,
     /// network to connect to, eg. `chipnet`
-    #[argh(option, default = "Network::default()")]
-    pub network: Network,
+    #[argh(option)]
+    pub network: Option<Network>,
     /// connect to this node
     #[argh(option)]
     pub connect: Vec<net::SocketAddr>,
     /// enable debug logging
     #[argh(switch)]
     pub debug: Option<bool>,
+    /// log output format, `text` or `json`
+    #[argh(option)]
+    pub log_format: Option<logger::Format>,
+    /// per-target log levels, eg. `nakamoto_p2p=warn,p2p_client=debug`
+    #[argh(option)]
+    pub log_filter: Option<String>,
+    /// write logs to this file instead of stderr
+    #[argh(option)]
+    pub log_file: Option<PathBuf>,
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opts = Options::from_env();
-    let client = Client::<Reactor>::new().unwrap();
-    let handle = client.handle();
-    let network = opts.network;
-    let connect = opts.connect;
-    let shutdown_tx = handle.clone();
-
-    let level = if opts.debug.is_some() {
-        log::Level::Debug
-    } else {
-        log::Level::Info
+    let overrides = Overrides {
+        network: opts.network,
+        data_dir: opts.data_dir,
+        connect: opts.connect,
+        debug: opts.debug.is_some(),
+        log_format: opts.log_format,
+        log_filter: opts.log_filter,
+        log_file: opts.log_file,
+        ..Overrides::default()
     };
+    let settings = Settings::load(opts.config.as_deref(), overrides)?;
+    let client = Client::<Reactor>::new()?;
+    let handle = client.handle();
+    let network = settings.network;
+    let fp_rate = settings.fp_rate;
+    let reactor = handle.clone();
+
+    let level = settings.log_level.unwrap_or(log::Level::Info);
 
     let (loading_tx, _loading_rx) = chan::unbounded();
     let (ui_show_tx, ui_show_rx) = chan::unbounded();
@@ -315,37 +73,72 @@ fn main() {
 
     let client_recv = handle.events();
 
-    let cfg = Config {
-        network,
-        connect,
-        listen: vec![],
-        ..Config::default()
+    let cfg = settings.client_config();
+
+    let filter = logger::Filter::new(level)
+        .with_directives(settings.log_filter.as_deref().unwrap_or_default())?;
+    let log_file = match &settings.log_file {
+        Some(path) => Some(logger::LogFile::open(path, settings.log_rotation.clone())?),
+        None => None,
     };
+    logger::init(filter, settings.log_format, log_file)?;
+    let watch_list = WatchList::open(WatchList::path(&cfg.root, network))?;
+    for entry in settings.watch {
+        ui_input_tx.send(UIMessage::AddWatchItem {
+            data: entry.item,
+            label: entry.label,
+            birthday: entry.birthday,
+        })?;
+    }
+    let shutdown = Shutdown::new();
 
-    logger::init(level).expect("initializing logger for the first time");
     let t1 = thread::spawn(move || client.load(cfg, loading_tx)?.run());
-    let t2 = thread::spawn(move || {
-        if let Err(err) =
-            Watcher::new(handle.clone(), network).run(&client_recv, &ui_input_rx, &ui_show_tx)
-        {
-            println!("FATAL ERR {:?}", err);
-            std::process::exit(1);
+    let t2 = thread::spawn({
+        let shutdown = shutdown.clone();
+        move || {
+            let watcher = Watcher::new(handle.clone(), network)
+                .auto_load_filter(true)
+                .fp_rate(fp_rate)
+                .with_shutdown(shutdown.clone())
+                .with_watch_list(watch_list);
+            let result = watcher.and_then(|mut w| w.run(&client_recv, &ui_input_rx, &ui_show_tx));
+            if let Err(err) = &result {
+                log::error!("Watcher failed: {}", err);
+            }
+            shutdown.trigger();
+            result
         }
     });
 
-    run_ui_main(&ui_input_tx, &ui_show_rx).expect("UI failed");
-    shutdown_tx.shutdown().unwrap();
+    run_ui_main(&ui_input_tx, &ui_show_rx, &shutdown)?;
+    shutdown.trigger();
     _ = slint::quit_event_loop();
-    t1.join().unwrap().unwrap();
-    t2.join().unwrap();
+
+    // The watcher flushes its state before the reactor goes away.
+    let watched = t2.join().map_err(|_| Error::ThreadPanicked("watcher"))?;
+    reactor.shutdown()?;
+    let reactor = t1.join().map_err(|_| Error::ThreadPanicked("reactor"))?;
+
+    watched?;
+    reactor?;
+    Ok(())
+}
+
+/// Send a message to the watcher, which may have stopped.
+fn send(ui_input_tx: &Sender<UIMessage>, msg: UIMessage) {
+    if ui_input_tx.send(msg).is_err() {
+        log::warn!("Watcher stopped, dropping message");
+    }
 }
 
 pub fn run_ui_main(
     ui_input_tx: &Sender<UIMessage>,
     ui_show_rx: &Receiver<UIMessage>,
+    shutdown: &Shutdown,
 ) -> Result<(), PlatformError> {
     let main_window = MainWindow::new()?;
     let ui_show_rx = ui_show_rx.clone();
+    let shutdown = shutdown.clone();
 
     let app = main_window.as_weak();
     let tx_handle = ui_input_tx.clone();
@@ -356,26 +149,22 @@ pub fn run_ui_main(
         let item = app.unwrap().get_bloom_item();
 
         if !item.is_empty() {
-            filter_items.push(item);
+            filter_items.push(item.clone());
             items.iter().for_each(|f| filter_items.push(f.clone()));
             app.unwrap().set_bloom_items(filter_items.clone().into());
-            filter_items.iter().for_each(|f| {
-                tx_handle
-                    .send(UIMessage::AddBloomItem(f.to_string()))
-                    .unwrap()
-            });
+            send(&tx_handle, UIMessage::AddBloomItem(item.to_string()));
         }
     });
     let app = main_window.as_weak();
 
     let ui_input_peers_tx = ui_input_tx.clone();
     app.unwrap().on_load_peers_filter(move || {
-        ui_input_peers_tx.send(UIMessage::SendLoadFilter).unwrap();
+        send(&ui_input_peers_tx, UIMessage::SendLoadFilter);
     });
 
     let ui_handle_tx = ui_input_tx.clone();
     app.unwrap().on_reset_filters(move || {
-        ui_handle_tx.send(UIMessage::ClearFilterAndPeers).unwrap();
+        send(&ui_handle_tx, UIMessage::ClearFilterAndPeers);
     });
 
     app.unwrap().on_copy_to_clipboard(move |text| {
@@ -419,14 +208,9 @@ pub fn run_ui_main(
             let end_height = end_height.max(begin_height);
 
             if end_height >= begin_height {
-                let range = MerkleScanRange {
-                    begin: begin_height,
-                    end: end_height,
-                };
+                let range = MerkleScanRange::new(begin_height, end_height);
                 log::info!("Requesting blocks: {:?}", range);
-                ui_input_tx_clone
-                    .send(UIMessage::RequestBlocks(range))
-                    .unwrap();
+                send(&ui_input_tx_clone, UIMessage::RequestBlocks(range));
             }
         }
     });
@@ -484,9 +268,10 @@ pub fn run_ui_main(
     std::thread::spawn(move || {
         loop {
             chan::select! {
+                recv(shutdown.receiver()) -> _ => break,
                 recv(ui_show_rx) -> event => {
                     if let Ok(event) = event {
-                        app.upgrade_in_event_loop(move |app| {
+                        let shown = app.upgrade_in_event_loop(move |app| {
                             match event {
                                 UIMessage::HeaderLoaded(height) => {
                                     app.set_loaded_header(height.to_string().into());
@@ -499,9 +284,17 @@ pub fn run_ui_main(
                                 UIMessage::AddBloomItem(item) => {
                                     app.set_bloom_item(item.into());
                                 }
+                                UIMessage::WatchListLoaded(items) => {
+                                    let items: Vec<SharedString> =
+                                        items.into_iter().map(|i| i.data.into()).collect();
+                                    app.set_bloom_items(ModelRc::new(slint::VecModel::from(items)));
+                                }
                                 UIMessage::ReceivedBlock(item) => {
                                     app.set_latest_merkle(item.to_string().into());
                                 }
+                                UIMessage::ScanProgress(progress) => {
+                                    app.set_scan_progress(progress.to_string().into());
+                                }
                                 UIMessage::BlocksDownloading(is_scanning) => {
                                     // let end_height = app.get_scan_end_height().to_string();
                                     // let end_height: u64 = match end_height.parse() {
@@ -531,36 +324,75 @@ pub fn run_ui_main(
                                 }
                                 UIMessage::PeerLoadedFilter(item) => {
                                     let peers:Vec<SharedString> = app.get_filtered_peers().iter().collect();
+                                    let item: SharedString = item.to_string().into();
+                                    if peers.contains(&item) {
+                                        return;
+                                    }
                                     let peers_model =
                                     std::rc::Rc::new(slint::VecModel::from(peers));
-                                    peers_model.push(item.to_string().into());
+                                    peers_model.push(item);
                                     app.set_filtered_peers(peers_model .clone().into());
                                 }
-                                UIMessage::ReceivedMatchedTx { transaction } => {
+                                UIMessage::TxStatusChanged { txid, status } => {
+                                    app.set_latest_status(format!("{} {}", txid, status).into());
+                                }
+                                UIMessage::BalanceUpdated { address, balance, tokens } => {
+                                    let mut line = format!(
+                                        "{}: {} sats ({} unconfirmed)",
+                                        address, balance.confirmed, balance.unconfirmed
+                                    );
+                                    for token in tokens {
+                                        line.push_str(&format!(", {}", token));
+                                    }
+                                    let line: SharedString = line.into();
+                                    let mut balances: Vec<SharedString> = app
+                                        .get_balances()
+                                        .iter()
+                                        .filter(|b| !b.starts_with(&format!("{}:", address)))
+                                        .collect();
+                                    balances.push(line);
+                                    app.set_balances(ModelRc::new(slint::VecModel::from(balances)));
+                                }
+                                UIMessage::ReceivedMatchedTx { transaction, tokens, .. } => {
                                     let txid = transaction.txid().to_string();
                                     app.set_matched_tx(txid.clone().into());
-                                    let txs:Vec<SharedString> = app.get_matched_txs().iter().collect();
+                                    let txs: Vec<MatchedTx> = app.get_matched_txs().iter().collect();
                                     let txs_model = std::rc::Rc::new(slint::VecModel::from(txs));
                                     let mut seen = std::collections::HashSet::new();
                                     txs_model.iter().for_each(|i| {
-                                        seen.insert(i.as_str().to_string());
+                                        seen.insert(i.txid.as_str().to_string());
                                     });
                                     if seen.insert(txid.clone()) {
-                                        txs_model.push(txid.into());
+                                        let tokens = tokens
+                                            .iter()
+                                            .map(|(vout, token)| format!("#{} {}", vout, token))
+                                            .collect::<Vec<_>>()
+                                            .join(", ");
+                                        txs_model.push(MatchedTx {
+                                            txid: txid.into(),
+                                            tokens: tokens.into(),
+                                        });
                                     }
                                     app.set_matched_txs(txs_model.clone().into());
                                 }
                                 _ => {},
                             }
-                        }).unwrap();
+                        });
+                        // The window was closed.
+                        if shown.is_err() {
+                            break;
+                        }
+                    } else {
+                        // The watcher stopped, there is nothing left to show.
+                        _ = slint::quit_event_loop();
+                        break;
                     }
                 }
             }
         }
     });
 
-    main_window.run().unwrap();
-    Ok(())
+    main_window.run()
 }
 
 impl Options {
@@ -0,0 +1,257 @@
+//! CashAddr decoding and locking scripts of watched addresses.
+//!
+//! Handles P2PKH, P2SH (20 byte) and P2SH32 addresses, including the token-aware
+//! `z` and `r` variants introduced with CashTokens.
+use std::fmt;
+
+use nakamoto_cash::client::Network;
+use nakamoto_cash::common::bitcoin::Script;
+
+use crate::error::Error;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const CHECKSUM_LEN: usize = 8;
+/// Prefixes tried when an address is given without one.
+const PREFIXES: [&str; 3] = ["bitcoincash", "bchtest", "bchreg"];
+
+const OP_DUP: u8 = 0x76;
+const OP_HASH160: u8 = 0xa9;
+const OP_HASH256: u8 = 0xaa;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_CHECKSIG: u8 = 0xac;
+
+/// Kind of locking script an address pays to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AddressType {
+    /// Pay to public key hash.
+    P2pkh,
+    /// Pay to script hash, with a 20 byte `HASH160`.
+    P2sh,
+    /// Pay to script hash, with a 32 byte `HASH256`.
+    P2sh32,
+}
+
+/// A decoded CashAddr.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub prefix: String,
+    pub kind: AddressType,
+    /// Whether the address signals that the receiver accepts CashTokens.
+    pub token_aware: bool,
+    /// Public key or script hash.
+    pub hash: Vec<u8>,
+}
+
+impl Address {
+    /// Decode a CashAddr, with or without its prefix.
+    pub fn decode(s: &str) -> Result<Self, Error> {
+        let lower = s.to_ascii_lowercase();
+        if lower != s && s.to_ascii_uppercase() != s {
+            // Mixed case is not allowed.
+            return Err(Error::CashAddressEncoding);
+        }
+        match lower.split_once(':') {
+            Some((prefix, payload)) => Self::decode_with_prefix(prefix, payload),
+            None => PREFIXES
+                .iter()
+                .find_map(|prefix| Self::decode_with_prefix(prefix, &lower).ok())
+                .ok_or(Error::CashAddressEncoding),
+        }
+    }
+
+    fn decode_with_prefix(prefix: &str, payload: &str) -> Result<Self, Error> {
+        let values = payload
+            .bytes()
+            .map(|c| CHARSET.iter().position(|x| *x == c).map(|p| p as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(Error::CashAddressEncoding)?;
+
+        if values.len() <= CHECKSUM_LEN || polymod(&checksum_input(prefix, &values)) != 0 {
+            return Err(Error::CashAddressEncoding);
+        }
+        let data = convert_bits(&values[..values.len() - CHECKSUM_LEN], 5, 8, false)
+            .ok_or(Error::CashAddressEncoding)?;
+        let (version, hash) = data.split_first().ok_or(Error::CashAddressEncoding)?;
+
+        if version & 0x80 != 0 {
+            return Err(Error::CashAddressEncoding);
+        }
+        let size = match version & 0x07 {
+            0 => 20,
+            1 => 24,
+            2 => 28,
+            3 => 32,
+            4 => 40,
+            5 => 48,
+            6 => 56,
+            _ => 64,
+        };
+        if hash.len() != size {
+            return Err(Error::CashAddressEncoding);
+        }
+        let (kind, token_aware) = match ((version >> 3) & 0x0f, size) {
+            (0, 20) => (AddressType::P2pkh, false),
+            (1, 20) => (AddressType::P2sh, false),
+            (1, 32) => (AddressType::P2sh32, false),
+            (2, 20) => (AddressType::P2pkh, true),
+            (3, 20) => (AddressType::P2sh, true),
+            (3, 32) => (AddressType::P2sh32, true),
+            _ => return Err(Error::CashAddressEncoding),
+        };
+
+        Ok(Self {
+            prefix: prefix.to_owned(),
+            kind,
+            token_aware,
+            hash: hash.to_vec(),
+        })
+    }
+
+    /// Build an address from a standard locking script, if it is one.
+    pub fn from_script(prefix: &str, script: &Script) -> Option<Self> {
+        let (kind, hash) = parse_script(script.as_bytes())?;
+        Some(Self {
+            prefix: prefix.to_owned(),
+            kind,
+            token_aware: false,
+            hash: hash.to_vec(),
+        })
+    }
+
+    /// The locking script paying to this address.
+    pub fn script_pubkey(&self) -> Script {
+        let mut script = Vec::with_capacity(35);
+        match self.kind {
+            AddressType::P2pkh => {
+                script.extend([OP_DUP, OP_HASH160, 20]);
+                script.extend(&self.hash);
+                script.extend([OP_EQUALVERIFY, OP_CHECKSIG]);
+            }
+            AddressType::P2sh => {
+                script.extend([OP_HASH160, 20]);
+                script.extend(&self.hash);
+                script.push(OP_EQUAL);
+            }
+            AddressType::P2sh32 => {
+                script.extend([OP_HASH256, 32]);
+                script.extend(&self.hash);
+                script.push(OP_EQUAL);
+            }
+        }
+        Script::from(script)
+    }
+
+    /// Encode as a CashAddr, including the prefix.
+    pub fn encode(&self) -> String {
+        let type_bits: u8 = match (self.kind, self.token_aware) {
+            (AddressType::P2pkh, false) => 0,
+            (AddressType::P2sh | AddressType::P2sh32, false) => 1,
+            (AddressType::P2pkh, true) => 2,
+            (AddressType::P2sh | AddressType::P2sh32, true) => 3,
+        };
+        let size_bits: u8 = if self.hash.len() == 32 { 3 } else { 0 };
+
+        let mut data = vec![(type_bits << 3) | size_bits];
+        data.extend(&self.hash);
+        let mut values = convert_bits(&data, 8, 5, true).expect("padding is allowed");
+
+        let mut input = checksum_input(&self.prefix, &values);
+        input.extend([0; CHECKSUM_LEN]);
+        let checksum = polymod(&input);
+        values.extend((0..CHECKSUM_LEN).map(|i| ((checksum >> (5 * (7 - i))) & 0x1f) as u8));
+
+        let payload = values
+            .iter()
+            .map(|v| CHARSET[*v as usize] as char)
+            .collect::<String>();
+        format!("{}:{}", self.prefix, payload)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+/// CashAddr prefix used on `network`.
+pub fn prefix(network: Network) -> &'static str {
+    match network {
+        Network::Mainnet => "bitcoincash",
+        _ => "bchtest",
+    }
+}
+
+/// Match a standard P2PKH, P2SH or P2SH32 locking script, returning its hash.
+pub fn parse_script(script: &[u8]) -> Option<(AddressType, &[u8])> {
+    match script {
+        [OP_DUP, OP_HASH160, 20, hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] if hash.len() == 20 => {
+            Some((AddressType::P2pkh, hash))
+        }
+        [OP_HASH160, 20, hash @ .., OP_EQUAL] if hash.len() == 20 => {
+            Some((AddressType::P2sh, hash))
+        }
+        [OP_HASH256, 32, hash @ .., OP_EQUAL] if hash.len() == 32 => {
+            Some((AddressType::P2sh32, hash))
+        }
+        _ => None,
+    }
+}
+
+/// Prefix and payload values as fed to the checksum.
+fn checksum_input(prefix: &str, values: &[u8]) -> Vec<u8> {
+    let mut input = prefix.bytes().map(|b| b & 0x1f).collect::<Vec<_>>();
+    input.push(0);
+    input.extend(values);
+    input
+}
+
+fn polymod(values: &[u8]) -> u64 {
+    let mut c: u64 = 1;
+    for v in values {
+        let c0 = (c >> 35) as u8;
+        c = ((c & 0x07_ffff_ffff) << 5) ^ *v as u64;
+        if c0 & 0x01 != 0 {
+            c ^= 0x98_f2bc_8e61;
+        }
+        if c0 & 0x02 != 0 {
+            c ^= 0x79_b76d_99e2;
+        }
+        if c0 & 0x04 != 0 {
+            c ^= 0xf3_3e5f_b3c4;
+        }
+        if c0 & 0x08 != 0 {
+            c ^= 0xae_2eab_e2a8;
+        }
+        if c0 & 0x10 != 0 {
+            c ^= 0x1e_4f43_e470;
+        }
+    }
+    c ^ 1
+}
+
+/// Regroup `data` from `from` bit values into `to` bit values.
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let mut out = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    let max = (1 << to) - 1;
+
+    for v in data {
+        acc = (acc << from) | *v as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            out.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max) != 0 {
+        return None;
+    }
+    Some(out)
+}
@@ -0,0 +1,127 @@
+//! Transactions submitted to the network, and how peers took them.
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use nakamoto_cash::chain::Transaction;
+use nakamoto_cash::common::bitcoin::consensus::encode::deserialize;
+use nakamoto_cash::p2p::PeerId;
+
+use crate::error::Error;
+
+/// How long a peer has to ask for a transaction submitted to it before it is
+/// considered to have rejected it.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What became of a transaction submitted to a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    /// Announced to the peer, which hasn't asked for it yet.
+    Submitted,
+    /// The peer asked for the transaction, ie. it is accepting it.
+    Acknowledged,
+    /// The peer disconnected without asking for the transaction.
+    Dropped,
+    /// The peer didn't ask for the transaction within [`ACK_TIMEOUT`], ie. it
+    /// isn't taking it.
+    Rejected,
+}
+
+impl fmt::Display for PeerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Submitted => write!(f, "submitted"),
+            Self::Acknowledged => write!(f, "acknowledged"),
+            Self::Dropped => write!(f, "dropped"),
+            Self::Rejected => write!(f, "rejected"),
+        }
+    }
+}
+
+/// A transaction we broadcast.
+#[derive(Clone, Debug)]
+pub struct Broadcast {
+    pub transaction: Transaction,
+    /// Peers the transaction was submitted to. Empty until a peer is available.
+    pub peers: HashMap<PeerId, PeerStatus>,
+    /// Whether the transaction came back to us through the bloom filter.
+    pub seen: bool,
+    /// When the transaction was last submitted.
+    pub submitted_at: Option<Instant>,
+}
+
+impl Broadcast {
+    pub fn new(transaction: Transaction) -> Self {
+        Self {
+            transaction,
+            peers: HashMap::new(),
+            seen: false,
+            submitted_at: None,
+        }
+    }
+
+    /// Whether the transaction is still waiting for a peer to submit it to:
+    /// no peer is considering or took it.
+    pub fn is_pending(&self) -> bool {
+        !self
+            .peers
+            .values()
+            .any(|s| matches!(s, PeerStatus::Submitted | PeerStatus::Acknowledged))
+    }
+
+    /// Record that the transaction was submitted to `peers` at `now`.
+    pub fn submitted(&mut self, peers: &[PeerId], now: Instant) {
+        for peer in peers {
+            self.peers.insert(*peer, PeerStatus::Submitted);
+        }
+        self.submitted_at = Some(now);
+    }
+
+    /// Mark the peers that didn't ask for the transaction within
+    /// [`ACK_TIMEOUT`] as having rejected it, and return them.
+    pub fn reject_unacknowledged(&mut self, now: Instant) -> Vec<PeerId> {
+        let Some(at) = self.submitted_at else {
+            return Vec::new();
+        };
+        if now.saturating_duration_since(at) < ACK_TIMEOUT {
+            return Vec::new();
+        }
+        self.peers
+            .iter_mut()
+            .filter(|(_, s)| **s == PeerStatus::Submitted)
+            .map(|(peer, s)| {
+                *s = PeerStatus::Rejected;
+                *peer
+            })
+            .collect()
+    }
+
+    /// Update the status of `peer`, returning whether it changed.
+    ///
+    /// A peer that acknowledged the transaction stays acknowledged, while a
+    /// peer that rejected it may still ask for it late.
+    pub fn set_status(&mut self, peer: PeerId, status: PeerStatus) -> bool {
+        match self.peers.get_mut(&peer) {
+            Some(PeerStatus::Acknowledged) => false,
+            Some(s) if *s == status => false,
+            Some(s) => {
+                *s = status;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Decode a raw transaction from hex, as accepted by [`UIMessage::BroadcastTx`].
+///
+/// [`UIMessage::BroadcastTx`]: crate::watcher::UIMessage::BroadcastTx
+pub fn decode(raw: &str) -> Result<Transaction, Error> {
+    let bytes = hex::decode(raw.trim()).map_err(|_| Error::Deserialization)?;
+    let tx: Transaction = deserialize(&bytes).map_err(|_| Error::Deserialization)?;
+
+    if tx.input.is_empty() || tx.output.is_empty() {
+        return Err(Error::Deserialization);
+    }
+    Ok(tx)
+}
@@ -0,0 +1,340 @@
+//! Configuration file, merged with command line flags.
+//!
+//! ```toml
+//! network = "chipnet"
+//! data_dir = "/var/lib/p2p-client"
+//! connect = ["127.0.0.1:48333"]
+//! listen = []
+//! max_outbound_peers = 8
+//! max_inbound_peers = 0
+//! user_agent = "/p2p-client:0.1.0/"
+//! fp_rate = 0.0001
+//! log_level = "info"
+//! log_format = "json"
+//! log_filter = "nakamoto_p2p=warn,p2p_client=debug"
+//!
+//! [log_file]
+//! path = "/var/log/p2p-client.log"
+//! max_size = 10485760
+//! max_age = 86400
+//! keep = 5
+//!
+//! [[watch]]
+//! item = "bchtest:qz..."
+//! label = "donations"
+//!
+//! [[xpub]]
+//! key = "xpub6C..."
+//! path = "m/44'/145'/0'"
+//! gap_limit = 20
+//! label = "shop"
+//!
+//! [rpc]
+//! listen = "127.0.0.1:8766"
+//! ```
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
+
+use nakamoto_cash::client::{Config, Network};
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::filter;
+use crate::hd;
+use crate::logger;
+
+/// File name of the configuration file, under the data directory.
+pub const FILE_NAME: &str = "p2p-client.toml";
+
+/// An item to watch, as listed in the configuration file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchEntry {
+    pub item: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Height to scan the item's history from, defaults to the tip.
+    #[serde(default)]
+    pub birthday: Option<u64>,
+}
+
+/// An HD account to watch, as listed in the configuration file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct XpubEntry {
+    /// Extended public key of the account.
+    pub key: String,
+    /// Derivation path of the key, defaults to `m/44'/145'/0'`.
+    #[serde(default = "default_xpub_path")]
+    pub path: String,
+    /// Defaults to the top-level `gap_limit`.
+    #[serde(default)]
+    pub gap_limit: Option<u32>,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Height to scan the account's history from, defaults to the tip.
+    #[serde(default)]
+    pub birthday: Option<u64>,
+}
+
+fn default_xpub_path() -> String {
+    hd::DEFAULT_PATH.to_owned()
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    /// Address to serve JSON-RPC requests on. The server has no
+    /// authentication, so this must be a loopback address.
+    pub listen: Option<SocketAddr>,
+}
+
+/// Log file sink and its rotation.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Rotate once the file reaches this many bytes.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Rotate once the file is this many seconds old.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// Number of rotated files to keep.
+    #[serde(default)]
+    pub keep: Option<usize>,
+}
+
+/// Contents of the configuration file. Every setting is optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub network: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub connect: Vec<SocketAddr>,
+    pub listen: Vec<SocketAddr>,
+    pub max_outbound_peers: Option<usize>,
+    pub max_inbound_peers: Option<usize>,
+    pub user_agent: Option<String>,
+    pub fp_rate: Option<f64>,
+    pub log_level: Option<String>,
+    pub log_format: Option<String>,
+    /// Per-target log levels, eg. `nakamoto_p2p=warn,p2p_client=debug`.
+    pub log_filter: Option<String>,
+    pub log_file: Option<LogFileConfig>,
+    pub watch: Vec<WatchEntry>,
+    pub xpub: Vec<XpubEntry>,
+    /// Unused addresses to derive past the last used one of HD accounts.
+    pub gap_limit: Option<u32>,
+    pub rpc: RpcConfig,
+}
+
+impl ConfigFile {
+    /// Default location of the configuration file: under the data directory if
+    /// one is given, and under the default client `root` otherwise.
+    pub fn default_path(data_dir: Option<&Path>) -> PathBuf {
+        match data_dir {
+            Some(dir) => dir.join(FILE_NAME),
+            None => Config::default().root.join(".nakamoto").join(FILE_NAME),
+        }
+    }
+
+    /// Parse the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let s = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        toml::from_str(&s).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Settings given on the command line, taking precedence over the file.
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub network: Option<Network>,
+    pub data_dir: Option<PathBuf>,
+    pub connect: Vec<SocketAddr>,
+    pub fp_rate: Option<f64>,
+    pub watch: Vec<String>,
+    pub xpub: Vec<String>,
+    pub xpub_path: Option<String>,
+    pub gap_limit: Option<u32>,
+    /// Birthday of the items and accounts given on the command line.
+    pub birthday: Option<u64>,
+    pub debug: bool,
+    pub log_format: Option<logger::Format>,
+    pub log_filter: Option<String>,
+    pub log_file: Option<PathBuf>,
+    pub rpc_listen: Option<SocketAddr>,
+}
+
+/// Settings resolved from the configuration file and command line.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub network: Network,
+    /// Data directory, holding one sub-directory per network.
+    pub data_dir: Option<PathBuf>,
+    /// Client root: the data directory, or the client's default root.
+    pub root: PathBuf,
+    pub connect: Vec<SocketAddr>,
+    pub listen: Vec<SocketAddr>,
+    pub max_outbound_peers: Option<usize>,
+    pub max_inbound_peers: Option<usize>,
+    pub user_agent: Option<String>,
+    pub fp_rate: f64,
+    pub log_level: Option<log::Level>,
+    pub log_format: logger::Format,
+    /// Per-target log levels, on top of `log_level`.
+    pub log_filter: Option<String>,
+    pub log_file: Option<PathBuf>,
+    pub log_rotation: logger::Rotation,
+    pub watch: Vec<WatchEntry>,
+    /// HD accounts, with their gap limit resolved.
+    pub xpubs: Vec<XpubEntry>,
+    pub rpc_listen: Option<SocketAddr>,
+}
+
+impl Settings {
+    /// Load the configuration file and apply command line `overrides`.
+    ///
+    /// When `path` is `None`, the file is looked up at its default location and
+    /// may be missing. Log directives not given on the command line are taken
+    /// from `RUST_LOG`, if set.
+    pub fn load(path: Option<&Path>, mut overrides: Overrides) -> Result<Self, Error> {
+        if overrides.log_filter.is_none() {
+            overrides.log_filter = env::var("RUST_LOG").ok();
+        }
+        let file = match path {
+            Some(path) => ConfigFile::load(path)?,
+            None => {
+                let path = ConfigFile::default_path(overrides.data_dir.as_deref());
+                if path.exists() {
+                    ConfigFile::load(&path)?
+                } else {
+                    ConfigFile::default()
+                }
+            }
+        };
+        Self::merge(file, overrides)
+    }
+
+    /// Merge a configuration file with command line `overrides`.
+    pub fn merge(file: ConfigFile, overrides: Overrides) -> Result<Self, Error> {
+        let network = match (overrides.network, file.network) {
+            (Some(network), _) => network,
+            (None, Some(name)) => Network::from_str(&name)
+                .map_err(|e| Error::Config(format!("network `{}`: {}", name, e)))?,
+            (None, None) => Network::default(),
+        };
+        let log_level = if overrides.debug {
+            Some(log::Level::Debug)
+        } else {
+            file.log_level
+                .map(|l| {
+                    log::Level::from_str(&l)
+                        .map_err(|_| Error::Config(format!("log level `{}`", l)))
+                })
+                .transpose()?
+        };
+        let log_format = match (overrides.log_format, file.log_format) {
+            (Some(format), _) => format,
+            (None, Some(name)) => logger::Format::from_str(&name).map_err(Error::Config)?,
+            (None, None) => logger::Format::default(),
+        };
+        let log_filter = overrides.log_filter.or(file.log_filter);
+        if let Some(directives) = &log_filter {
+            logger::Filter::new(log::Level::Error)
+                .with_directives(directives)
+                .map_err(Error::Config)?;
+        }
+        let log_file = overrides
+            .log_file
+            .or_else(|| file.log_file.as_ref().map(|f| f.path.clone()));
+        let mut log_rotation = logger::Rotation::default();
+        if let Some(f) = file.log_file {
+            if f.max_size.is_some() || f.max_age.is_some() {
+                log_rotation.max_size = f.max_size;
+                log_rotation.max_age = f.max_age.map(Duration::from_secs);
+            }
+            log_rotation.keep = f.keep.unwrap_or(log_rotation.keep);
+        }
+        let connect = if overrides.connect.is_empty() {
+            file.connect
+        } else {
+            overrides.connect
+        };
+        let data_dir = overrides.data_dir.or(file.data_dir);
+        // The client keeps each network under `.nakamoto/<network>` of its
+        // root, and so do the watch list and wallets.
+        let root = data_dir.clone().unwrap_or_else(|| Config::default().root);
+        let mut watch = file.watch;
+        watch.extend(overrides.watch.into_iter().map(|item| WatchEntry {
+            item,
+            label: None,
+            birthday: overrides.birthday,
+        }));
+        let gap_limit = overrides
+            .gap_limit
+            .or(file.gap_limit)
+            .unwrap_or(hd::DEFAULT_GAP_LIMIT);
+        let xpub_path = overrides.xpub_path.unwrap_or_else(default_xpub_path);
+        let mut xpubs = file.xpub;
+        xpubs.extend(overrides.xpub.into_iter().map(|key| XpubEntry {
+            key,
+            path: xpub_path.clone(),
+            gap_limit: None,
+            label: None,
+            birthday: overrides.birthday,
+        }));
+        for entry in xpubs.iter_mut() {
+            entry.gap_limit.get_or_insert(gap_limit);
+        }
+
+        Ok(Self {
+            network,
+            data_dir,
+            root,
+            connect,
+            listen: file.listen,
+            max_outbound_peers: file.max_outbound_peers,
+            max_inbound_peers: file.max_inbound_peers,
+            user_agent: file.user_agent,
+            fp_rate: overrides
+                .fp_rate
+                .or(file.fp_rate)
+                .unwrap_or(filter::DEFAULT_FP_RATE),
+            log_level,
+            log_format,
+            log_filter,
+            log_file,
+            log_rotation,
+            watch,
+            xpubs,
+            rpc_listen: overrides.rpc_listen.or(file.rpc.listen),
+        })
+    }
+
+    /// Client configuration for these settings.
+    pub fn client_config(&self) -> Config {
+        let mut cfg = Config {
+            network: self.network,
+            connect: self.connect.clone(),
+            listen: self.listen.clone(),
+            root: self.root.clone(),
+            ..Config::default()
+        };
+        if let Some(n) = self.max_outbound_peers {
+            cfg.limits.max_outbound_peers = n;
+        }
+        if let Some(n) = self.max_inbound_peers {
+            cfg.limits.max_inbound_peers = n;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            // The client expects a static string; settings live for the whole process.
+            cfg.user_agent = Box::leak(user_agent.clone().into_boxed_str());
+        }
+        cfg
+    }
+}
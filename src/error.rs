@@ -0,0 +1,56 @@
+//! Errors of the library.
+use nakamoto_cash::client::handle;
+use thiserror::Error;
+
+/// An error occurring in the client, the watcher or the wallet.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("client handle error: {0}")]
+    Handle(#[from] handle::Error),
+    #[error("client error: {0}")]
+    Client(#[from] nakamoto_cash::client::Error),
+    #[error("channel error: {0}")]
+    Channel(#[from] crossbeam_channel::RecvError),
+    #[error("failed to load keys: {0}")]
+    Loading(std::io::ErrorKind),
+    #[error("file system write error")]
+    WriteError,
+    #[error("cash address encoding")]
+    CashAddressEncoding,
+    #[error("failed to deserialize")]
+    Deserialization,
+    #[error("file system read error: {0}")]
+    ReadWallet(String),
+    #[error("wallet name not found")]
+    WalletNameNotFound,
+    #[error("not a pay to public key hash script")]
+    Script,
+    #[error("bad derivation path")]
+    XprivPath,
+    #[error("invalid extended public key: {0}")]
+    Xpub(String),
+    #[error("invalid token prefix: {0}")]
+    Token(String),
+    #[error("invalid merkle block at height {0}: {1}")]
+    InvalidMerkleBlock(u64, String),
+    #[error("configuration error: {0}")]
+    Config(String),
+    #[error("script not found")]
+    ScriptNotFound,
+    #[error("scalar bytes")]
+    InvalidScalarBytes,
+    #[error("dust limit")]
+    Dust,
+    #[error("insufficient funds: {needed} needed, {available} available")]
+    InsufficientFunds { needed: u64, available: u64 },
+    #[error("failed to handle signals: {0}")]
+    Signal(String),
+    #[error("failed to initialize logging: {0}")]
+    Logger(String),
+    #[error("channel disconnected")]
+    Disconnected,
+    #[error("{0} thread panicked")]
+    ThreadPanicked(&'static str),
+    #[error("file system io error: {0}")]
+    Io(#[from] std::io::Error),
+}
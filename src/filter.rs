@@ -0,0 +1,76 @@
+//! Bloom filter construction.
+use std::collections::BTreeSet;
+
+use nakamoto_cash::common::bitcoin::util::bloom::{Bloom, BloomFilter};
+
+/// Default false-positive rate of the filter.
+pub const DEFAULT_FP_RATE: f64 = 0.01;
+
+/// Tracks the full set of filter elements, and builds a bloom filter sized for them.
+#[derive(Clone, Debug)]
+pub struct FilterBuilder {
+    elements: BTreeSet<Vec<u8>>,
+    fp_rate: f64,
+}
+
+impl Default for FilterBuilder {
+    fn default() -> Self {
+        Self::new(DEFAULT_FP_RATE)
+    }
+}
+
+impl FilterBuilder {
+    pub fn new(fp_rate: f64) -> Self {
+        Self {
+            elements: BTreeSet::new(),
+            fp_rate,
+        }
+    }
+
+    pub fn fp_rate(&self) -> f64 {
+        self.fp_rate
+    }
+
+    pub fn set_fp_rate(&mut self, fp_rate: f64) {
+        self.fp_rate = fp_rate;
+    }
+
+    /// Add an element, returns `false` if it was already present.
+    pub fn insert(&mut self, element: Vec<u8>) -> bool {
+        self.elements.insert(element)
+    }
+
+    /// Remove an element, returns `false` if it wasn't present.
+    pub fn remove(&mut self, element: &[u8]) -> bool {
+        self.elements.remove(element)
+    }
+
+    pub fn contains(&self, element: &[u8]) -> bool {
+        self.elements.contains(element)
+    }
+
+    pub fn clear(&mut self) {
+        self.elements.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &[u8]> {
+        self.elements.iter().map(|e| e.as_slice())
+    }
+
+    /// Build a filter holding every element, sized for the element count.
+    pub fn build(&self) -> BloomFilter {
+        let mut bloom = Bloom::<u8>::new_for_fp_rate(self.elements.len().max(1), self.fp_rate);
+        for element in &self.elements {
+            bloom.set(&mut element.clone());
+        }
+        BloomFilter::from(bloom)
+    }
+}
@@ -0,0 +1,220 @@
+//! Watch-only HD accounts: P2PKH addresses derived from an extended public key.
+//!
+//! The extended public key is the account key, eg. at `m/44'/145'/0'`. Receive
+//! addresses are derived at `<account>/0/i` and change addresses at
+//! `<account>/1/i`, as far as [`HdAccount::gap_limit`] unused addresses past
+//! the last used one.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use nakamoto_cash::client::Network;
+use nakamoto_cash::common::bitcoin::hashes::{Hash, hash160};
+use nakamoto_cash::common::bitcoin::secp256k1::Secp256k1;
+use nakamoto_cash::common::bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey};
+use nakamoto_cash::common::bitcoin::Script;
+use serde::{Deserialize, Serialize};
+
+use crate::address::{self, Address, AddressType};
+use crate::error::Error;
+
+/// Unused addresses derived past the last used one, per chain.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// BCH account path of BIP44.
+pub const DEFAULT_PATH: &str = "m/44'/145'/0'";
+
+/// Split user input of the form `<xpub> [path]` into the key and its path,
+/// or `None` if it doesn't look like an extended public key.
+pub fn parse_input(data: &str) -> Option<(&str, &str)> {
+    let data = data.trim();
+    if !(data.starts_with("xpub") || data.starts_with("tpub")) {
+        return None;
+    }
+    Some(
+        data.split_once(char::is_whitespace)
+            .map(|(xpub, path)| (xpub, path.trim()))
+            .unwrap_or((data, DEFAULT_PATH)),
+    )
+}
+
+/// Receive or change addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Chain {
+    Receive,
+    Change,
+}
+
+impl Chain {
+    fn index(self) -> usize {
+        match self {
+            Self::Receive => 0,
+            Self::Change => 1,
+        }
+    }
+}
+
+/// An account as stored in the watch list.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HdRecord {
+    pub xpub: String,
+    pub path: String,
+    pub gap_limit: u32,
+    /// Highest used receive index.
+    #[serde(default)]
+    pub used_receive: Option<u32>,
+    /// Highest used change index.
+    #[serde(default)]
+    pub used_change: Option<u32>,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Height of the first block the account may appear in, see
+    /// [`WatchItem::birthday`](crate::watchlist::WatchItem::birthday).
+    #[serde(default)]
+    pub birthday: Option<u64>,
+}
+
+/// A watch-only account, deriving addresses from an extended public key.
+#[derive(Clone, Debug)]
+pub struct HdAccount {
+    pub xpub: ExtendedPubKey,
+    pub path: DerivationPath,
+    pub gap_limit: u32,
+    pub label: Option<String>,
+    pub birthday: Option<u64>,
+    prefix: &'static str,
+    /// Receive and change chain keys.
+    chains: [ExtendedPubKey; 2],
+    /// Number of addresses derived, per chain.
+    derived: [u32; 2],
+    /// Highest used index, per chain.
+    used: [Option<u32>; 2],
+    scripts: HashMap<Script, (Chain, u32)>,
+}
+
+impl HdAccount {
+    /// Create an account from an extended public key and the path it was
+    /// derived at. No address is derived until [`HdAccount::extend`].
+    pub fn new(xpub: &str, path: &str, gap_limit: u32, network: Network) -> Result<Self, Error> {
+        let xpub = ExtendedPubKey::from_str(xpub.trim()).map_err(|e| Error::Xpub(e.to_string()))?;
+        let path = DerivationPath::from_str(path.trim()).map_err(|_| Error::XprivPath)?;
+
+        // The path must lead to the key we were given.
+        if path.len() != xpub.depth as usize
+            || path.as_ref().last().is_some_and(|c| *c != xpub.child_number)
+        {
+            return Err(Error::XprivPath);
+        }
+        if gap_limit == 0 {
+            return Err(Error::Config("gap limit must be positive".to_owned()));
+        }
+        let secp = Secp256k1::verification_only();
+        let chain = |i| {
+            ChildNumber::from_normal_idx(i)
+                .and_then(|c| xpub.ckd_pub(&secp, c))
+                .map_err(|e| Error::Xpub(e.to_string()))
+        };
+        let chains = [chain(0)?, chain(1)?];
+
+        Ok(Self {
+            xpub,
+            path,
+            gap_limit,
+            label: None,
+            birthday: None,
+            prefix: address::prefix(network),
+            chains,
+            derived: [0; 2],
+            used: [None; 2],
+            scripts: HashMap::new(),
+        })
+    }
+
+    /// Restore an account from the watch list.
+    pub fn from_record(record: &HdRecord, network: Network) -> Result<Self, Error> {
+        let mut account = Self::new(&record.xpub, &record.path, record.gap_limit, network)?;
+        account.label = record.label.clone();
+        account.birthday = record.birthday;
+        account.used = [record.used_receive, record.used_change];
+
+        Ok(account)
+    }
+
+    /// The account as stored in the watch list.
+    pub fn record(&self) -> HdRecord {
+        HdRecord {
+            xpub: self.xpub.to_string(),
+            path: self.path.to_string(),
+            gap_limit: self.gap_limit,
+            used_receive: self.used[Chain::Receive.index()],
+            used_change: self.used[Chain::Change.index()],
+            label: self.label.clone(),
+            birthday: self.birthday,
+        }
+    }
+
+    /// Address at `index` of `chain`.
+    pub fn derive(&self, chain: Chain, index: u32) -> Result<Address, Error> {
+        let secp = Secp256k1::verification_only();
+        let key = ChildNumber::from_normal_idx(index)
+            .and_then(|c| self.chains[chain.index()].ckd_pub(&secp, c))
+            .map_err(|e| Error::Xpub(e.to_string()))?;
+        let hash = hash160::Hash::hash(&key.public_key.serialize());
+
+        Ok(Address {
+            prefix: self.prefix.to_owned(),
+            kind: AddressType::P2pkh,
+            token_aware: false,
+            hash: hash.into_inner().to_vec(),
+        })
+    }
+
+    /// Derive addresses until each chain has `gap_limit` unused addresses past
+    /// its last used one.
+    ///
+    /// Returns the newly derived addresses.
+    pub fn extend(&mut self) -> Result<Vec<Address>, Error> {
+        let mut addresses = Vec::new();
+
+        for chain in [Chain::Receive, Chain::Change] {
+            let i = chain.index();
+            let target = self.used[i].map_or(0, |u| u + 1) + self.gap_limit;
+
+            while self.derived[i] < target {
+                let address = self.derive(chain, self.derived[i])?;
+                self.scripts
+                    .insert(address.script_pubkey(), (chain, self.derived[i]));
+                self.derived[i] += 1;
+                addresses.push(address);
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Chain and index of a derived script, if it belongs to this account.
+    pub fn index_of(&self, script: &Script) -> Option<(Chain, u32)> {
+        self.scripts.get(script).copied()
+    }
+
+    /// Record that `script` received funds.
+    ///
+    /// Returns `true` if it moved the last used index, ie. more addresses
+    /// may need deriving.
+    pub fn mark_used(&mut self, script: &Script) -> bool {
+        let Some((chain, index)) = self.index_of(script) else {
+            return false;
+        };
+        let used = &mut self.used[chain.index()];
+        if used.is_some_and(|u| u >= index) {
+            return false;
+        }
+        *used = Some(index);
+        true
+    }
+
+    /// All addresses derived so far.
+    pub fn addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        self.scripts
+            .keys()
+            .filter_map(|script| Address::from_script(self.prefix, script))
+    }
+}
@@ -0,0 +1,161 @@
+//! Confirmation status of matched transactions.
+use std::collections::HashMap;
+use std::fmt;
+
+use nakamoto_cash::common::bitcoin::{BlockHash, Txid};
+
+use crate::merkle::Confirmation;
+
+/// Status changes are reported until a transaction is this deep.
+pub const REPORT_DEPTH: u64 = 6;
+
+/// Where a matched transaction stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Not seen in any block yet.
+    Mempool,
+    /// Proven to be in a block of the active chain.
+    Confirmed {
+        height: u64,
+        block_hash: BlockHash,
+        confirmations: u64,
+    },
+    /// The confirming block was disconnected by a reorg.
+    Reverted { height: u64, block_hash: BlockHash },
+}
+
+impl fmt::Display for TxStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mempool => write!(f, "mempool"),
+            Self::Confirmed {
+                height,
+                confirmations,
+                ..
+            } => write!(f, "confirmed at {} ({} confirmations)", height, confirmations),
+            Self::Reverted { height, .. } => write!(f, "reverted from {}", height),
+        }
+    }
+}
+
+/// Status of every matched transaction.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    txs: HashMap<Txid, TxStatus>,
+    tip: u64,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the chain tip used to count confirmations.
+    pub fn set_tip(&mut self, tip: u64) {
+        self.tip = tip;
+    }
+
+    pub fn status(&self, txid: &Txid) -> Option<TxStatus> {
+        self.txs.get(txid).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Txid, &TxStatus)> {
+        self.txs.iter()
+    }
+
+    /// Record a matched transaction, or a proof for one already known.
+    ///
+    /// Returns the new status if it changed.
+    pub fn update(&mut self, txid: Txid, confirmation: Option<Confirmation>) -> Option<TxStatus> {
+        let status = match confirmation {
+            Some(c) => self.confirmed(c.height, c.block_hash),
+            // A proof always wins over a later unconfirmed sighting.
+            None => match self.txs.get(&txid) {
+                Some(s) if !matches!(s, TxStatus::Reverted { .. }) => return None,
+                _ => TxStatus::Mempool,
+            },
+        };
+        self.set(txid, status)
+    }
+
+    /// Record a proof only if the transaction is already tracked.
+    pub fn confirm(&mut self, txid: Txid, confirmation: Confirmation) -> Option<TxStatus> {
+        if !self.txs.contains_key(&txid) {
+            return None;
+        }
+        self.update(txid, Some(confirmation))
+    }
+
+    /// A block was connected: update confirmation counts.
+    ///
+    /// Returns the changes worth reporting, ie. for transactions that aren't
+    /// deeper than [`REPORT_DEPTH`].
+    pub fn block_connected(&mut self, height: u64) -> Vec<(Txid, TxStatus)> {
+        self.tip = height;
+        self.recount()
+    }
+
+    /// A block was disconnected: revert transactions confirmed in it, and
+    /// update confirmation counts of the others.
+    pub fn block_disconnected(&mut self, height: u64, hash: BlockHash) -> Vec<(Txid, TxStatus)> {
+        self.tip = height.saturating_sub(1);
+
+        let mut changed = Vec::new();
+        for (txid, status) in self.txs.iter_mut() {
+            if let TxStatus::Confirmed { block_hash, .. } = status {
+                if *block_hash == hash {
+                    *status = TxStatus::Reverted {
+                        height,
+                        block_hash: hash,
+                    };
+                    changed.push((*txid, *status));
+                }
+            }
+        }
+        changed.extend(self.recount());
+        changed
+    }
+
+    pub fn clear(&mut self) {
+        self.txs.clear();
+    }
+
+    /// Count confirmations against the tip again.
+    ///
+    /// Returns the changes worth reporting.
+    fn recount(&mut self) -> Vec<(Txid, TxStatus)> {
+        let mut changed = Vec::new();
+        for (txid, status) in self.txs.iter_mut() {
+            if let TxStatus::Confirmed {
+                height,
+                confirmations,
+                ..
+            } = status
+            {
+                let depth = self.tip.saturating_sub(*height) + 1;
+                if depth != *confirmations {
+                    *confirmations = depth;
+                    if depth <= REPORT_DEPTH {
+                        changed.push((*txid, *status));
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    fn confirmed(&self, height: u64, block_hash: BlockHash) -> TxStatus {
+        TxStatus::Confirmed {
+            height,
+            block_hash,
+            confirmations: self.tip.saturating_sub(height) + 1,
+        }
+    }
+
+    fn set(&mut self, txid: Txid, status: TxStatus) -> Option<TxStatus> {
+        if self.txs.insert(txid, status) == Some(status) {
+            return None;
+        }
+        Some(status)
+    }
+}
@@ -0,0 +1,180 @@
+//! Password based encryption of wallet files.
+//!
+//! An encrypted file is a header followed by the sealed payload:
+//!
+//! | bytes | field                                   |
+//! |-------|-----------------------------------------|
+//! | 4     | magic, `P2PW`                           |
+//! | 1     | format version, currently 1             |
+//! | 1     | scrypt `log_n`                          |
+//! | 4     | scrypt `r`, little-endian               |
+//! | 4     | scrypt `p`, little-endian               |
+//! | 16    | salt                                    |
+//! | 12    | nonce                                   |
+//! | ..    | ChaCha20-Poly1305 ciphertext and tag    |
+//!
+//! The key is derived from the password with scrypt, and the header is
+//! authenticated along with the payload.
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+use crate::error::Error;
+
+pub const MAGIC: &[u8; 4] = b"P2PW";
+pub const VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 4 + 4 + SALT_LEN + NONCE_LEN;
+
+/// Highest scrypt costs accepted from a file header, so that a corrupt file
+/// can't make us allocate more than 128 MiB.
+const MAX_LOG_N: u8 = 20;
+const MAX_R: u32 = 32;
+const MAX_P: u32 = 16;
+const MAX_MEMORY: u64 = 128 << 20;
+
+/// scrypt cost parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Whether deriving a key with these costs takes a reasonable amount of
+    /// memory and time.
+    fn is_sane(&self) -> bool {
+        self.log_n <= MAX_LOG_N
+            && self.r <= MAX_R
+            && self.p <= MAX_P
+            && (128 * u64::from(self.r)) << self.log_n <= MAX_MEMORY
+    }
+}
+
+/// A key derived from a password, used to seal successive versions of a file.
+///
+/// The key is wiped from memory when dropped.
+#[derive(Clone)]
+pub struct Keystore {
+    key: Zeroizing<[u8; 32]>,
+    salt: [u8; SALT_LEN],
+    params: KdfParams,
+}
+
+impl Keystore {
+    /// Derive a key from `password` with a new random salt.
+    pub fn new(password: &str) -> Result<Self, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Self::derive(password, salt, KdfParams::default())
+    }
+
+    fn derive(password: &str, salt: [u8; SALT_LEN], params: KdfParams) -> Result<Self, Error> {
+        let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p, 32)
+            .map_err(|_| Error::ReadWallet("invalid key derivation parameters".to_owned()))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        scrypt::scrypt(password.as_bytes(), &salt, &scrypt_params, key.as_mut())
+            .map_err(|_| Error::ReadWallet("key derivation failed".to_owned()))?;
+
+        Ok(Self { key, salt, params })
+    }
+
+    /// Whether `data` looks like an encrypted file.
+    pub fn is_sealed(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Encrypt `plaintext` under a new nonce.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut data = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        data.extend(MAGIC);
+        data.push(VERSION);
+        data.push(self.params.log_n);
+        data.extend(self.params.r.to_le_bytes());
+        data.extend(self.params.p.to_le_bytes());
+        data.extend(self.salt);
+        data.extend(nonce);
+
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &data,
+                },
+            )
+            .map_err(|_| Error::WriteError)?;
+        data.extend(ciphertext);
+
+        Ok(data)
+    }
+
+    /// Decrypt a sealed file with `password`, returning the plaintext and the
+    /// key to seal it again with.
+    pub fn open(password: &str, data: &[u8]) -> Result<(Vec<u8>, Self), Error> {
+        if !Self::is_sealed(data) {
+            return Err(Error::ReadWallet("not an encrypted wallet".to_owned()));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(Error::ReadWallet("truncated wallet file".to_owned()));
+        }
+        let (header, ciphertext) = data.split_at(HEADER_LEN);
+        let version = header[4];
+        if version != VERSION {
+            return Err(Error::ReadWallet(format!(
+                "unsupported wallet format version {}",
+                version
+            )));
+        }
+        let params = KdfParams {
+            log_n: header[5],
+            r: u32::from_le_bytes(header[6..10].try_into().expect("4 bytes")),
+            p: u32::from_le_bytes(header[10..14].try_into().expect("4 bytes")),
+        };
+        if !params.is_sane() {
+            return Err(Error::ReadWallet(
+                "corrupt wallet file: key derivation parameters out of range".to_owned(),
+            ));
+        }
+        let salt = header[14..14 + SALT_LEN].try_into().expect("salt length");
+        let nonce = &header[14 + SALT_LEN..];
+
+        let keystore = Self::derive(password, salt, params)?;
+        let plaintext = keystore
+            .cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            // The tag doesn't tell a wrong password from a damaged file.
+            .map_err(|_| Error::ReadWallet("wrong password or corrupt wallet file".to_owned()))?;
+
+        Ok((plaintext, keystore))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(self.key.as_ref()))
+    }
+}
@@ -0,0 +1,37 @@
+//! Bitcoin Cash P2P light client.
+//!
+//! Watch-only building blocks on top of `nakamoto-cash`, shared by the
+//! headless `p2p-client` binary and the `watch-demo` UI.
+pub mod address;
+pub mod broadcast;
+pub mod config;
+pub mod error;
+pub mod filter;
+pub mod hd;
+pub mod history;
+pub mod keystore;
+pub mod logger;
+pub mod merkle;
+pub mod rpc;
+pub mod scan;
+pub mod shutdown;
+pub mod token;
+pub mod utxo;
+pub mod wallet;
+pub mod watcher;
+pub mod watchlist;
+
+pub use address::{Address, AddressType};
+pub use broadcast::{Broadcast, PeerStatus};
+pub use error::Error;
+pub use filter::FilterBuilder;
+pub use hd::{Chain, HdAccount};
+pub use history::{History, TxStatus};
+pub use merkle::Confirmation;
+pub use scan::{MerkleScanRange, ScanProgress};
+pub use shutdown::Shutdown;
+pub use token::{Category, TokenBalance, TokenData};
+pub use utxo::{Balance, Utxo, UtxoSet};
+pub use wallet::Wallet;
+pub use watcher::{FilterState, UIMessage, Watcher};
+pub use watchlist::{WatchItem, WatchKind, WatchList};
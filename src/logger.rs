@@ -1,45 +1,314 @@
 //! Logging module.
-use std::{io};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use chrono::prelude::*;
-use chrono::{DateTime};
-use log::{Level, Log, Metadata, Record, SetLoggerError};
+use chrono::{DateTime, Utc};
+use log::kv::{self, Key, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Value, json};
+
+/// Output format of log records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// `{timestamp} {message}`, one record per line.
+    #[default]
+    Text,
+    /// One JSON object per line, with the timestamp, level, target, message,
+    /// source location and key-value fields of the record.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Per-target log levels, in the style of `RUST_LOG`.
+///
+/// Directives are comma separated: `target=level` sets the level of a target
+/// and its sub-modules, and a bare `level` sets the default, eg.
+/// `info,nakamoto_p2p=warn,p2p_client::watcher=debug`.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    default: LevelFilter,
+    /// Target prefixes and their level, longest first.
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// A filter applying `level` to every target.
+    pub fn new(level: Level) -> Self {
+        Self {
+            default: level.to_level_filter(),
+            directives: Vec::new(),
+        }
+    }
+
+    /// Add the comma separated `directives`, overriding earlier ones.
+    pub fn with_directives(mut self, directives: &str) -> Result<Self, String> {
+        for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = parse_level(level)?;
+                    self.directives.retain(|(t, _)| t != target);
+                    self.directives.push((target.to_owned(), level));
+                }
+                None => self.default = parse_level(directive)?,
+            }
+        }
+        self.directives.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+
+        Ok(self)
+    }
+
+    /// Whether a record of `target` at `level` is logged.
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        let max = self
+            .directives
+            .iter()
+            .find(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level);
+
+        level <= max
+    }
+
+    /// Most verbose level of any target.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(s.trim()).map_err(|_| format!("unknown log level `{}`", s))
+}
+
+/// When to rotate a log file.
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    /// Rotate once the file reaches this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate once the file was written to for this long.
+    pub max_age: Option<Duration>,
+    /// Number of rotated files to keep, as `<path>.1` (newest) to `<path>.<keep>`.
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: Some(10 * 1024 * 1024),
+            max_age: None,
+            keep: 5,
+        }
+    }
+}
+
+/// A log file, rotated according to a [`Rotation`].
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl LogFile {
+    /// Open the log file at `path` for appending, creating it if needed.
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size,
+            opened: SystemTime::now(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.is_due(len) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+
+        Ok(())
+    }
+
+    fn is_due(&self, len: u64) -> bool {
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size + len > max);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max| self.opened.elapsed().is_ok_and(|age| age >= max));
+        too_big || too_old
+    }
+
+    /// Shift the rotated files up by one and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+enum Sink {
+    Stderr(io::Stderr),
+    File(Mutex<LogFile>),
+}
 
 struct Logger {
-    level: Level,
-    stream: io::Stderr,
+    filter: Filter,
+    format: Format,
+    sink: Sink,
 }
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level
+        self.filter.enabled(metadata.target(), metadata.level())
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            write(record, &self.stream);
+            let now = DateTime::<Utc>::from(std::time::SystemTime::now())
+                .to_rfc3339_opts(SecondsFormat::Millis, true);
+            let line = match self.format {
+                Format::Text => format!("{} {}", now, record.args()),
+                Format::Json => json_record(&now, record).to_string(),
+            };
 
-            fn write(record: &log::Record, mut stream: impl io::Write) {
-                let now = DateTime::from(std::time::SystemTime::now())
-                    .to_rfc3339_opts(SecondsFormat::Millis, true);
-                writeln!(stream, "{} {}", now, record.args()).expect("write shouldn't fail");
+            match &self.sink {
+                Sink::Stderr(stream) => {
+                    writeln!(stream.lock(), "{}", line).expect("write shouldn't fail")
+                }
+                Sink::File(file) => {
+                    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                    if let Err(e) = file.write_line(&line) {
+                        // There's nowhere else to log to.
+                        eprintln!("{} {}: {}", now, file.path().display(), e);
+                    }
+                }
             }
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        if let Sink::File(file) = &self.sink {
+            file.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .file
+                .flush()
+                .ok();
+        }
+    }
 }
 
-/// Initialize a new logger.
-pub fn init(level: Level) -> Result<(), SetLoggerError> {
+/// Collects the key-value fields of a record.
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), Value::String(value.to_string()));
+        Ok(())
+    }
+}
+
+fn json_record(timestamp: &str, record: &Record) -> Value {
+    let mut fields = Fields(Map::new());
+    // Visiting only fails if the visitor does, which ours doesn't.
+    record.key_values().visit(&mut fields).ok();
+
+    json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "module": record.module_path(),
+        "file": record.file(),
+        "line": record.line(),
+        "fields": fields.0,
+    })
+}
+
+/// Initialize a new logger, writing to `file` if given and to stderr otherwise.
+pub fn init(filter: Filter, format: Format, file: Option<LogFile>) -> Result<(), SetLoggerError> {
+    let max_level = filter.max_level();
     let logger = Logger {
-        level,
-        stream: io::stderr(),
+        filter,
+        format,
+        sink: match file {
+            Some(file) => Sink::File(Mutex::new(file)),
+            None => Sink::Stderr(io::stderr()),
+        },
     };
 
     log::set_boxed_logger(Box::new(logger))?;
-    log::set_max_level(level.to_level_filter());
+    log::set_max_level(max_level);
 
     Ok(())
 }
-
@@ -1,77 +1,589 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 use std::{env, net, thread};
 use argh::FromArgs;
-use client::{Client, Config};
+use client::Client;
 use nakamoto_cash::client::traits::Handle;
 use nakamoto_cash::client::{self, Network};
 type Reactor = nakamoto_cash::net::poll::Reactor<net::TcpStream>;
-use crossbeam_channel::{self as chan};
+use crossbeam_channel::{self as chan, Receiver, RecvTimeoutError, Sender};
 
-pub mod logger;
-/// A Bitcoin wallet.
+use p2p_client::config::{Overrides, Settings};
+use p2p_client::{hd, logger, rpc, wallet};
+use p2p_client::{
+    Error, MerkleScanRange, PeerStatus, Shutdown, UIMessage, Wallet, WatchList, Watcher,
+};
+
+/// Environment variable holding the wallet password, to run unattended.
+const PASSWORD_VAR: &str = "P2P_CLIENT_WALLET_PASSWORD";
+
+/// A headless Bitcoin Cash watch-only client.
 #[derive(FromArgs)]
 pub struct Options {
-    /// network to connect to, eg. `chipnet
-    #[argh(option, default = "Network::default()")]
-    pub network: Network,
+    /// configuration file, defaults to `p2p-client.toml` in the data directory
+    #[argh(option)]
+    pub config: Option<PathBuf>,
+    /// data directory, with one sub-directory per network
+    #[argh(option)]
+    pub data_dir: Option<PathBuf>,
+    /// network to connect to, eg. `chipnet`
+    #[argh(option)]
+    pub network: Option<Network>,
     /// connect to this node
     #[argh(option)]
     pub connect: Vec<net::SocketAddr>,
-    // / wallet file
-    // #[argh(option)]
-    // pub wallet: Option<PathBuf>,
+    /// wallet file, whose addresses are watched and spent from on request
+    #[argh(option)]
+    pub wallet: Option<PathBuf>,
+    /// cashaddr, hex script, txid or `token:<category>` to watch
+    #[argh(option)]
+    pub watch: Vec<String>,
+    /// extended public key of an HD account to watch
+    #[argh(option)]
+    pub xpub: Vec<String>,
+    /// derivation path of the `--xpub` keys, defaults to `m/44'/145'/0'`
+    #[argh(option)]
+    pub xpub_path: Option<String>,
+    /// unused addresses to derive past the last used one, defaults to 20
+    #[argh(option)]
+    pub gap_limit: Option<u32>,
+    /// height to scan the history of `--watch` and `--xpub` items from,
+    /// defaults to the tip
+    #[argh(option)]
+    pub birthday: Option<u64>,
+    /// rescan merkle blocks from this height up to the tip
+    #[argh(option)]
+    pub scan_from: Option<u64>,
+    /// bloom filter false-positive rate
+    #[argh(option)]
+    pub fp_rate: Option<f64>,
+    /// serve unauthenticated JSON-RPC requests on this loopback address, eg. `127.0.0.1:8766`
+    #[argh(option)]
+    pub rpc_listen: Option<net::SocketAddr>,
     /// enable debug logging
     #[argh(switch)]
     pub debug: Option<bool>,
+    /// log output format, `text` or `json`
+    #[argh(option)]
+    pub log_format: Option<logger::Format>,
+    /// per-target log levels, eg. `nakamoto_p2p=warn,p2p_client=debug`
+    #[argh(option)]
+    pub log_filter: Option<String>,
+    /// write logs to this file instead of stderr
+    #[argh(option)]
+    pub log_file: Option<PathBuf>,
+    #[argh(subcommand)]
+    pub command: Option<Subcommand>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Subcommand {
+    Broadcast(BroadcastCommand),
+    Wallet(WalletCommand),
+    Send(SendCommand),
+}
 
-fn main() {
+/// Broadcast a raw transaction and report how peers take it.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "broadcast")]
+pub struct BroadcastCommand {
+    /// raw transaction, in hex
+    #[argh(positional)]
+    pub tx: String,
+    /// seconds to wait for the transaction to be relayed back to us
+    #[argh(option, default = "60")]
+    pub timeout: u64,
+}
 
-    let opts = Options::from_env();
-    let client = Client::<Reactor>::new().unwrap();
+/// Manage signing wallets.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "wallet")]
+pub struct WalletCommand {
+    #[argh(subcommand)]
+    pub action: WalletAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum WalletAction {
+    Create(WalletCreate),
+    Address(WalletAddress),
+    Encrypt(WalletEncrypt),
+    List(WalletListCommand),
+}
+
+/// Create a wallet with a new random key.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "create")]
+pub struct WalletCreate {
+    /// wallet name
+    #[argh(positional)]
+    pub name: String,
+}
+
+/// Hand out a new receive address.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "address")]
+pub struct WalletAddress {
+    /// wallet name
+    #[argh(positional)]
+    pub name: String,
+}
+
+/// Encrypt a wallet, or change its password.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "encrypt")]
+pub struct WalletEncrypt {
+    /// wallet name
+    #[argh(positional)]
+    pub name: String,
+}
+
+/// List wallets.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+pub struct WalletListCommand {}
+
+/// Pay to an address from a wallet, and broadcast the transaction.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "send")]
+pub struct SendCommand {
+    /// wallet name
+    #[argh(positional)]
+    pub wallet: String,
+    /// cashaddr to pay to
+    #[argh(positional)]
+    pub address: String,
+    /// amount, in satoshis
+    #[argh(positional)]
+    pub amount: u64,
+    /// rescan the wallet's history from this height before paying
+    #[argh(option)]
+    pub scan_from: u64,
+    /// fee rate, in satoshis per byte
+    #[argh(option, default = "wallet::DEFAULT_FEE_RATE")]
+    pub fee_rate: u64,
+    /// seconds to wait for the transaction to be relayed back to us
+    #[argh(option, default = "60")]
+    pub timeout: u64,
+}
+
+fn main() -> ExitCode {
+    match run(Options::from_env()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(opts: Options) -> Result<ExitCode, Error> {
+    let overrides = Overrides {
+        network: opts.network,
+        data_dir: opts.data_dir,
+        connect: opts.connect,
+        fp_rate: opts.fp_rate,
+        watch: opts.watch,
+        xpub: opts.xpub,
+        xpub_path: opts.xpub_path,
+        gap_limit: opts.gap_limit,
+        birthday: opts.birthday,
+        debug: opts.debug.is_some(),
+        log_format: opts.log_format,
+        log_filter: opts.log_filter,
+        log_file: opts.log_file,
+        rpc_listen: opts.rpc_listen,
+    };
+    let settings = Settings::load(opts.config.as_deref(), overrides)?;
+    if let Some(Subcommand::Wallet(cmd)) = &opts.command {
+        run_wallet(cmd, &settings)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let wallet_path = match (&opts.command, &opts.wallet) {
+        (Some(Subcommand::Send(cmd)), _) => {
+            let dir = Wallet::dir(&settings.root, settings.network);
+            Some(Wallet::file_path(&dir, &cmd.wallet)?)
+        }
+        (_, Some(path)) => Some(path.clone()),
+        _ => None,
+    };
+    let wallet = wallet_path
+        .map(|path| open_wallet(&path, settings.network))
+        .transpose()?;
+    let client = Client::<Reactor>::new()?;
     let handle = client.handle();
-    let network = opts.network;
-    let addrs = opts.connect;
-    let connect = addrs;
-
-    let level = if opts.debug.is_some() {
-        log::Level::Debug
-    } else {
-        log::Level::Error
+    let network = settings.network;
+    let fp_rate = settings.fp_rate;
+    let level = settings.log_level.unwrap_or(log::Level::Error);
+
+    let (loading_tx, _loading_rx) = chan::unbounded();
+    let (ui_show_tx, ui_show_rx) = chan::unbounded();
+    let (ui_input_tx, ui_input_rx) = chan::unbounded();
+
+    let client_recv = handle.events();
+
+    let cfg = settings.client_config();
+
+    let filter = logger::Filter::new(level)
+        .with_directives(settings.log_filter.as_deref().unwrap_or_default())
+        .map_err(Error::Config)?;
+    let log_file = match &settings.log_file {
+        Some(path) => Some(logger::LogFile::open(path, settings.log_rotation.clone())?),
+        None => None,
+    };
+    logger::init(filter, settings.log_format, log_file)
+        .map_err(|e| Error::Logger(e.to_string()))?;
+
+    let shutdown = Shutdown::new();
+    shutdown.on_signals()?;
+
+    let watch_list = WatchList::open(WatchList::path(&cfg.root, network))?;
+
+    for entry in settings.watch {
+        ui_input_tx
+            .send(UIMessage::AddWatchItem {
+                data: entry.item,
+                label: entry.label,
+                birthday: entry.birthday,
+            })
+            .map_err(|_| Error::Disconnected)?;
+    }
+
+    for entry in settings.xpubs {
+        ui_input_tx
+            .send(UIMessage::AddXpub {
+                xpub: entry.key,
+                path: entry.path,
+                gap_limit: entry.gap_limit.unwrap_or(hd::DEFAULT_GAP_LIMIT),
+                label: entry.label,
+                birthday: entry.birthday,
+            })
+            .map_err(|_| Error::Disconnected)?;
+    }
+
+    if let Some(addr) = settings.rpc_listen {
+        let server = rpc::Server::bind(addr, handle.clone(), ui_input_tx.clone())?;
+        thread::spawn(move || {
+            if let Err(err) = server.run() {
+                log::error!("RPC server failed: {}", err);
+            }
+        });
+    }
+
+    let mut payment = None;
+    let broadcast = match opts.command {
+        Some(Subcommand::Broadcast(cmd)) => {
+            ui_input_tx
+                .send(UIMessage::BroadcastTx(cmd.tx))
+                .map_err(|_| Error::Disconnected)?;
+            Some(Duration::from_secs(cmd.timeout))
+        }
+        Some(Subcommand::Send(cmd)) => {
+            let msg = UIMessage::SendPayment {
+                address: cmd.address,
+                amount: cmd.amount,
+                fee_rate: cmd.fee_rate,
+            };
+            payment = Some((msg, cmd.scan_from));
+            Some(Duration::from_secs(cmd.timeout))
+        }
+        Some(Subcommand::Wallet(_)) | None => None,
     };
+    let reactor = handle.clone();
 
+    let t1 = thread::spawn(move || client.load(cfg, loading_tx)?.run());
+    let t2 = thread::spawn({
+        let shutdown = shutdown.clone();
+        move || {
+            let watcher = Watcher::new(handle, network)
+                .auto_load_filter(true)
+                .fp_rate(fp_rate)
+                .with_shutdown(shutdown.clone());
+            let watcher = match wallet {
+                Some(wallet) => watcher.with_wallet(wallet),
+                None => watcher,
+            }
+            .with_watch_list(watch_list);
+            let result = watcher.and_then(|mut w| w.run(&client_recv, &ui_input_rx, &ui_show_tx));
+            // Whatever stopped the watcher stops everyone else.
+            shutdown.trigger();
+            result
+        }
+    });
 
-    let (tx, rx) = chan::unbounded();
-    
-    let cfg = Config {
-        network,
-        connect,
-        // root: PathBuf::from(env::var(HOME_DIR).unwrap_or_default()),
-        listen: vec![], // Don't listen for incoming connections.
-        ..Config::default()
+    let code = match broadcast {
+        Some(timeout) => {
+            let accepted = match payment {
+                Some((msg, scan_from)) => {
+                    run_send(&ui_input_tx, &ui_show_rx, msg, scan_from, timeout)
+                }
+                None => run_broadcast(&ui_show_rx, timeout),
+            };
+            if accepted {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        None => {
+            run_headless(&ui_input_tx, &ui_show_rx, opts.scan_from);
+            ExitCode::SUCCESS
+        }
     };
 
-    logger::init(level).expect("initializing logger for the first time");
+    // The watcher flushes its state before the reactor goes away.
+    shutdown.trigger();
+    let watched = t2.join().map_err(|_| Error::ThreadPanicked("watcher"))?;
+    reactor.shutdown()?;
+    let reactor = t1.join().map_err(|_| Error::ThreadPanicked("reactor"))?;
+
+    watched?;
+    reactor?;
+    Ok(code)
+}
+
+/// Print watcher output to stdout, standing in for the UI.
+fn run_headless(
+    ui_input_tx: &Sender<UIMessage>,
+    ui_show_rx: &Receiver<UIMessage>,
+    scan_from: Option<u64>,
+) {
+    let mut tip = 0;
+    let mut scan_requested = scan_from.is_none();
+
+    for msg in ui_show_rx.iter() {
+        match msg {
+            UIMessage::NetworkConnected(network) => {
+                println!("network {:?}", network);
+            }
+            UIMessage::HeaderLoaded(height) => {
+                tip = height;
+                println!("headers loaded up to {}", height);
+            }
+            UIMessage::BlockConnected(height) => {
+                tip = height;
+                println!("block connected {}", height);
+            }
+            UIMessage::WatchListLoaded(items) => {
+                for item in items {
+                    match item.label {
+                        Some(label) => println!("watching {} ({})", item.data, label),
+                        None => println!("watching {}", item.data),
+                    }
+                }
+            }
+            UIMessage::PeerLoadedFilter(peer) => {
+                println!("peer {} loaded filter", peer);
+                if let (false, Some(begin)) = (scan_requested, scan_from) {
+                    let range = MerkleScanRange::new(begin, tip.max(begin));
+                    log::info!("Requesting blocks: {:?}", range);
+                    if ui_input_tx.send(UIMessage::RequestBlocks(range)).is_err() {
+                        break;
+                    }
+                    scan_requested = true;
+                }
+            }
+            UIMessage::BlocksDownloading(true) => {
+                println!("merkle scan started");
+            }
+            UIMessage::BlocksDownloading(false) => {
+                println!("merkle scan finished");
+            }
+            UIMessage::ReceivedBlock(height) => {
+                println!("merkle block {}/{}", height, tip);
+            }
+            UIMessage::ScanProgress(progress) => {
+                println!("merkle scan {}", progress);
+            }
+            UIMessage::ReceivedMatchedTx {
+                transaction,
+                confirmation,
+                tokens,
+            } => {
+                let txid = transaction.txid();
+                match confirmation {
+                    Some(c) => println!("matched tx {} in block {} at {}", txid, c.height, c.index),
+                    None => println!("matched tx {} (unconfirmed)", txid),
+                }
+                for (vout, token) in tokens {
+                    println!("  output {} token {}", vout, token);
+                }
+            }
+            UIMessage::TxStatusChanged { txid, status } => {
+                println!("tx {} {}", txid, status);
+            }
+            UIMessage::AccountExtended { xpub, addresses } => {
+                for address in addresses {
+                    println!("watching {} (from {})", address, xpub);
+                }
+            }
+            UIMessage::BalanceUpdated {
+                address,
+                balance,
+                tokens,
+            } => {
+                println!(
+                    "balance {} confirmed {} unconfirmed {}",
+                    address, balance.confirmed, balance.unconfirmed
+                );
+                for token in tokens {
+                    println!("  tokens {}", token);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Create wallets and hand out addresses, without connecting to the network.
+fn run_wallet(cmd: &WalletCommand, settings: &Settings) -> Result<(), Error> {
+    let dir = Wallet::dir(&settings.root, settings.network);
 
-  let t1 = thread::spawn(move || client.load(cfg, tx)?.run());
+    match &cmd.action {
+        WalletAction::Create(c) => {
+            let password = wallet_password(true)?;
+            let wallet = Wallet::create(&dir, &c.name, settings.network, &password)?;
+            println!("created {}", wallet.path().display());
+            println!("xpub {}", wallet.xpub());
+            println!("{}", wallet.receive_address()?);
+        }
+        WalletAction::Address(c) => {
+            let mut wallet = open_wallet(&Wallet::file_path(&dir, &c.name)?, settings.network)?;
+            println!("{}", wallet.new_address()?);
+        }
+        WalletAction::Encrypt(c) => {
+            let mut wallet = open_wallet(&Wallet::file_path(&dir, &c.name)?, settings.network)?;
+            println!("new password");
+            wallet.set_password(&wallet_password(true)?)?;
+            println!("encrypted {}", wallet.path().display());
+        }
+        WalletAction::List(_) => {
+            for name in Wallet::list(&dir)? {
+                println!("{}", name);
+            }
+        }
+    }
+    Ok(())
+}
 
+/// Open a wallet file, asking for its password if it is encrypted.
+fn open_wallet(path: &Path, network: Network) -> Result<Wallet, Error> {
+    let password = match Wallet::is_encrypted(path)? {
+        true => Some(wallet_password(false)?),
+        false => None,
+    };
+    Wallet::open(path, network, password.as_deref())
+}
 
-  t1.join().unwrap().unwrap();
+/// Wallet password, from the environment or the terminal.
+fn wallet_password(confirm: bool) -> Result<String, Error> {
+    if let Ok(password) = env::var(PASSWORD_VAR) {
+        return Ok(password);
+    }
+    let password = rpassword::prompt_password("wallet password: ")?;
+    if confirm && rpassword::prompt_password("confirm password: ")? != password {
+        return Err(Error::ReadWallet("passwords don't match".to_owned()));
+    }
+    Ok(password)
+}
 
+/// Rescan the wallet's history from `scan_from` to find its coins, then send
+/// `payment` and report its fate like [`run_broadcast`].
+fn run_send(
+    ui_input_tx: &Sender<UIMessage>,
+    ui_show_rx: &Receiver<UIMessage>,
+    payment: UIMessage,
+    scan_from: u64,
+    timeout: Duration,
+) -> bool {
+    let mut tip = 0;
+    let mut requested = None;
 
-    // thread::spawn(move || {
-    //     (0..10).for_each(|i| {
-    //         tx.send(i).unwrap();
-    //     })
-    // });
+    for msg in ui_show_rx.iter() {
+        match msg {
+            UIMessage::HeaderLoaded(height) | UIMessage::BlockConnected(height) => {
+                tip = height;
+            }
+            UIMessage::PeerLoadedFilter(_) if requested.is_none() => {
+                let range = MerkleScanRange::new(scan_from, tip.max(scan_from));
+                println!("scanning wallet history from {} to {}", range.begin(), range.end());
+                if ui_input_tx.send(UIMessage::RequestBlocks(range.clone())).is_err() {
+                    return false;
+                }
+                requested = Some(range);
+            }
+            UIMessage::BalanceUpdated { address, balance, .. } => {
+                println!(
+                    "balance {} confirmed {} unconfirmed {}",
+                    address, balance.confirmed, balance.unconfirmed
+                );
+            }
+            UIMessage::ScanProgress(progress) => {
+                let Some(range) = &requested else {
+                    continue;
+                };
+                println!("scanned {}", progress);
+                // A scan started before ours, eg. catching up with the tip,
+                // may complete before ours is added to it.
+                if !progress.covers(range) {
+                    continue;
+                }
+                if ui_input_tx.send(payment).is_err() {
+                    return false;
+                }
+                return run_broadcast(ui_show_rx, timeout);
+            }
+            _ => {}
+        }
+    }
+    false
+}
 
-    // let received: u32 = rx.iter().sum();
+/// Report the fate of a broadcast transaction, until it is relayed back to us
+/// or `timeout` passes without news.
+///
+/// Returns whether any peer accepted the transaction.
+fn run_broadcast(ui_show_rx: &Receiver<UIMessage>, timeout: Duration) -> bool {
+    let mut deadline = Instant::now() + timeout;
+    let mut accepted = false;
 
-    // assert_eq!((0..10).sum::<u32>(), received);
+    loop {
+        let msg = match ui_show_rx.recv_deadline(deadline) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => {
+                println!("not relayed back within {}s", timeout.as_secs());
+                break;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match msg {
+            UIMessage::TxBroadcast { txid, peer, status } => {
+                println!("tx {} {} ({})", txid, status, peer);
+                match status {
+                    PeerStatus::Submitted => deadline = Instant::now() + timeout,
+                    PeerStatus::Acknowledged => accepted = true,
+                    PeerStatus::Dropped | PeerStatus::Rejected => {}
+                }
+            }
+            UIMessage::BroadcastSeen(txid) => {
+                println!("tx {} relayed back", txid);
+                return true;
+            }
+            UIMessage::BroadcastFailed(reason) => {
+                println!("broadcast failed: {}", reason);
+                return false;
+            }
+            _ => {}
+        }
+    }
+    accepted
 }
+
 impl Options {
     pub fn from_env() -> Self {
         argh::from_env()
     }
 }
-
@@ -0,0 +1,56 @@
+//! Verification of merkle blocks against the header chain.
+use nakamoto_cash::client::traits::Handle;
+use nakamoto_cash::common::bitcoin::util::merkleblock::MerkleBlock;
+use nakamoto_cash::common::bitcoin::{BlockHash, Txid};
+
+use crate::error::Error;
+
+/// Block a transaction was proven to be included in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    pub height: u64,
+    pub block_hash: BlockHash,
+    /// Position of the transaction in the block.
+    pub index: u32,
+}
+
+/// Check that `merkle_block` is the block we have at `height`, and that its
+/// partial merkle tree commits to the header's merkle root.
+///
+/// Returns the txids proven to be part of the block.
+pub fn verify<H: Handle>(
+    client: &H,
+    merkle_block: &MerkleBlock,
+    height: u64,
+) -> Result<Vec<(Txid, Confirmation)>, Error> {
+    let block_hash = merkle_block.header.block_hash();
+    let header = client
+        .get_block_by_height(height)?
+        .ok_or_else(|| Error::InvalidMerkleBlock(height, "unknown height".to_owned()))?;
+
+    if header.block_hash() != block_hash {
+        return Err(Error::InvalidMerkleBlock(
+            height,
+            format!("block {} is not in the active chain", block_hash),
+        ));
+    }
+
+    let mut matches = Vec::new();
+    let mut indexes = Vec::new();
+    merkle_block
+        .extract_matches(&mut matches, &mut indexes)
+        .map_err(|e| Error::InvalidMerkleBlock(height, format!("{:?}", e)))?;
+
+    Ok(matches
+        .into_iter()
+        .zip(indexes)
+        .map(|(txid, index)| {
+            let confirmation = Confirmation {
+                height,
+                block_hash,
+                index,
+            };
+            (txid, confirmation)
+        })
+        .collect())
+}
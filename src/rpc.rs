@@ -0,0 +1,318 @@
+//! Line-delimited JSON-RPC 2.0 server driving the watcher over TCP.
+//!
+//! Each request is a single line of JSON, answered by a single line. Supported
+//! methods:
+//!
+//! * `watch` `{"item": .., "label": .., "birthday": ..}`: add a cashaddr, hex
+//!   script, txid or `token:<category>`, scanning its history from the
+//!   `birthday` height if given.
+//! * `watch_xpub` `{"xpub": .., "path": .., "gap_limit": .., "label": ..,
+//!   "birthday": ..}`: watch the addresses of an HD account.
+//! * `unwatch` `{"item": ..}`: remove a watched item or HD account.
+//! * `load_filter`: load the filter onto all negotiated peers.
+//! * `rescan` `{"from": .., "to": ..}`: rescan merkle blocks in a height range.
+//! * `list_transactions`: matched transactions and their status.
+//! * `get_tip`: height and hash of the chain tip.
+//!
+//! There is no authentication: anyone who can connect controls the watcher, so
+//! the server must only be bound to a loopback address.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{self as chan, Sender};
+use nakamoto_cash::client::traits::Handle;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::hd;
+use crate::history::TxStatus;
+use crate::scan::MerkleScanRange;
+use crate::watcher::UIMessage;
+
+/// How long to wait for the watcher to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a connection may stay idle, or block on a write, before it is
+/// closed.
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest request line, in bytes. The connection is dropped past it.
+const MAX_LINE: u64 = 64 * 1024;
+/// Connections served at once, others are refused.
+const MAX_CONNECTIONS: usize = 16;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct WatchParams {
+    item: String,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    birthday: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct WatchXpubParams {
+    xpub: String,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    gap_limit: Option<u32>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    birthday: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RescanParams {
+    from: u64,
+    to: u64,
+}
+
+/// An RPC error, turned into a JSON-RPC error object.
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// JSON-RPC server, forwarding requests to the watcher as [`UIMessage`]s.
+pub struct Server<H> {
+    listener: TcpListener,
+    client: H,
+    ui_input_tx: Sender<UIMessage>,
+    connections: Arc<AtomicUsize>,
+}
+
+/// Counts a connection as served until dropped.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn open(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(connections.clone()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<H: Handle + 'static> Server<H> {
+    /// Bind the server to `addr`, which should be a loopback address.
+    pub fn bind(addr: SocketAddr, client: H, ui_input_tx: Sender<UIMessage>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("RPC server listening on {}", listener.local_addr()?);
+        if !addr.ip().is_loopback() {
+            log::warn!(
+                "RPC server is not bound to loopback, anyone reaching {} controls the watcher",
+                addr
+            );
+        }
+
+        Ok(Self {
+            listener,
+            client,
+            ui_input_tx,
+            connections: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections until the listener fails, serving each on its own
+    /// thread, up to [`MAX_CONNECTIONS`] at once.
+    pub fn run(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr().ok();
+            let Some(connection) = Connection::open(&self.connections) else {
+                log::warn!("Refusing RPC connection {:?}: too many connections", peer);
+                continue;
+            };
+            let client = self.client.clone();
+            let ui_input_tx = self.ui_input_tx.clone();
+
+            thread::spawn(move || {
+                let _connection = connection;
+                if let Err(e) = serve(stream, &client, &ui_input_tx) {
+                    log::debug!("RPC connection {:?} closed: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve<H: Handle>(stream: TcpStream, client: &H, ui_input_tx: &Sender<UIMessage>) -> io::Result<()> {
+    // Clones share the timeouts, which make reads and writes fail, closing
+    // the connection.
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        let len = (&mut reader)
+            .take(MAX_LINE + 1)
+            .read_until(b'\n', &mut line)?;
+        if len == 0 {
+            break;
+        }
+        if len as u64 > MAX_LINE {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "request line too long");
+            return Err(err);
+        }
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_slice::<Request>(&line) {
+            Ok(req) => match dispatch(&req, client, ui_input_tx) {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": req.id, "result": result }),
+                Err(e) => error_response(req.id, e),
+            },
+            Err(e) => error_response(Value::Null, RpcError::new(PARSE_ERROR, e)),
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": err.code, "message": err.message },
+    })
+}
+
+fn dispatch<H: Handle>(
+    req: &Request,
+    client: &H,
+    ui_input_tx: &Sender<UIMessage>,
+) -> Result<Value, RpcError> {
+    let send = |msg: UIMessage| {
+        ui_input_tx
+            .send(msg)
+            .map_err(|_| RpcError::new(INTERNAL_ERROR, "watcher is not running"))
+    };
+
+    match req.method.as_str() {
+        "watch" => {
+            let p: WatchParams = params(&req.params)?;
+            send(UIMessage::AddWatchItem {
+                data: p.item,
+                label: p.label,
+                birthday: p.birthday,
+            })?;
+            Ok(Value::Bool(true))
+        }
+        "watch_xpub" => {
+            let p: WatchXpubParams = params(&req.params)?;
+            send(UIMessage::AddXpub {
+                xpub: p.xpub,
+                path: p.path.unwrap_or_else(|| hd::DEFAULT_PATH.to_owned()),
+                gap_limit: p.gap_limit.unwrap_or(hd::DEFAULT_GAP_LIMIT),
+                label: p.label,
+                birthday: p.birthday,
+            })?;
+            Ok(Value::Bool(true))
+        }
+        "unwatch" => {
+            let p: WatchParams = params(&req.params)?;
+            send(UIMessage::RemoveBloomItem(p.item))?;
+            Ok(Value::Bool(true))
+        }
+        "load_filter" => {
+            send(UIMessage::SendLoadFilter)?;
+            Ok(Value::Bool(true))
+        }
+        "rescan" => {
+            let p: RescanParams = params(&req.params)?;
+            if p.to < p.from {
+                return Err(RpcError::new(INVALID_PARAMS, "`to` is below `from`"));
+            }
+            send(UIMessage::RequestBlocks(MerkleScanRange::new(p.from, p.to)))?;
+            Ok(Value::Bool(true))
+        }
+        "list_transactions" => {
+            let (reply_tx, reply_rx) = chan::bounded(1);
+            send(UIMessage::ListMatchedTxs(reply_tx))?;
+            let txs = reply_rx
+                .recv_timeout(QUERY_TIMEOUT)
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e))?;
+
+            Ok(Value::Array(
+                txs.into_iter()
+                    .map(|(txid, status)| json!({ "txid": txid.to_string(), "status": status_json(status) }))
+                    .collect(),
+            ))
+        }
+        "get_tip" => {
+            let (height, header) = client
+                .get_tip()
+                .map_err(|e| RpcError::new(INTERNAL_ERROR, e))?;
+            Ok(json!({ "height": height, "hash": header.block_hash().to_string() }))
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method `{}`", req.method))),
+    }
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: &Value) -> Result<T, RpcError> {
+    serde_json::from_value(params.clone()).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn status_json(status: Option<TxStatus>) -> Value {
+    match status {
+        None | Some(TxStatus::Mempool) => json!({ "state": "mempool" }),
+        Some(TxStatus::Confirmed {
+            height,
+            block_hash,
+            confirmations,
+        }) => json!({
+            "state": "confirmed",
+            "height": height,
+            "block_hash": block_hash.to_string(),
+            "confirmations": confirmations,
+        }),
+        Some(TxStatus::Reverted { height, block_hash }) => json!({
+            "state": "reverted",
+            "height": height,
+            "block_hash": block_hash.to_string(),
+        }),
+    }
+}
@@ -0,0 +1,374 @@
+//! Merkle block rescans, split into chunks spread over the filtered peers.
+//!
+//! Each chunk is scanned by a single peer, and is done once the merkle block
+//! at its last height was received. Chunks of a peer that goes away, stops or
+//! stalls are handed out again, and chunks are persisted so that a scan
+//! resumes after a restart.
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use nakamoto_cash::p2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Blocks scanned by a peer in one go.
+pub const DEFAULT_CHUNK_SIZE: u64 = 1_000;
+
+/// How long a peer may go without sending a block of its chunk before the
+/// chunk is handed out again.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An inclusive range of block heights to rescan for merkle blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleScanRange {
+    begin: u64,
+    end: u64,
+}
+
+impl MerkleScanRange {
+    pub fn new(begin: u64, end: u64) -> Self {
+        Self { begin, end }
+    }
+
+    /// First height of the range.
+    pub fn begin(&self) -> u64 {
+        self.begin
+    }
+
+    /// Last height of the range.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Number of blocks in the range.
+    pub fn len(&self) -> u64 {
+        (self.end + 1).saturating_sub(self.begin)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A part of a scan, as stored in the watch list.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    begin: u64,
+    end: u64,
+    #[serde(default)]
+    done: bool,
+    /// Peer scanning the chunk.
+    #[serde(skip)]
+    peer: Option<PeerId>,
+    /// Highest height received from the peer.
+    #[serde(skip)]
+    received: Option<u64>,
+    /// When the peer was given the chunk, or last sent a block of it.
+    #[serde(skip)]
+    active_at: Option<Instant>,
+}
+
+impl Chunk {
+    fn new(begin: u64, end: u64) -> Self {
+        Self {
+            begin,
+            end,
+            done: false,
+            peer: None,
+            received: None,
+            active_at: None,
+        }
+    }
+
+    /// Hand the chunk back, so that it is scanned again.
+    fn release(&mut self) -> MerkleScanRange {
+        self.peer = None;
+        self.received = None;
+        self.active_at = None;
+
+        self.range()
+    }
+
+    pub fn range(&self) -> MerkleScanRange {
+        MerkleScanRange::new(self.begin, self.end)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Blocks of the chunk scanned so far.
+    fn scanned(&self) -> u64 {
+        match (self.done, self.received) {
+            (true, _) => self.range().len(),
+            (false, Some(height)) => height + 1 - self.begin,
+            (false, None) => 0,
+        }
+    }
+}
+
+/// How far a scan got.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanProgress {
+    /// Blocks scanned so far.
+    pub scanned: u64,
+    /// Blocks to scan in total.
+    pub total: u64,
+    /// Estimated time left, once some blocks were scanned.
+    pub eta: Option<Duration>,
+    /// Heights of the scan, as contiguous ranges.
+    pub ranges: Vec<MerkleScanRange>,
+}
+
+impl ScanProgress {
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        self.scanned as f64 * 100.0 / self.total as f64
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.scanned >= self.total
+    }
+
+    /// Whether the scan is done, and every height of `range` was part of it.
+    pub fn covers(&self, range: &MerkleScanRange) -> bool {
+        self.is_done()
+            && self
+                .ranges
+                .iter()
+                .any(|r| r.begin <= range.begin && range.end <= r.end)
+    }
+}
+
+impl fmt::Display for ScanProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}% of {} blocks", self.percent(), self.total)?;
+        if let Some(eta) = self.eta.filter(|_| !self.is_done()) {
+            let secs = eta.as_secs();
+            match secs {
+                0..60 => write!(f, ", {}s left", secs)?,
+                60..3600 => write!(f, ", {}m{:02}s left", secs / 60, secs % 60)?,
+                _ => write!(f, ", {}h{:02}m left", secs / 3600, secs % 3600 / 60)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A rescan in progress.
+#[derive(Clone, Debug)]
+pub struct Scan {
+    /// Chunks by height, never overlapping.
+    chunks: Vec<Chunk>,
+    chunk_size: u64,
+    started_at: Instant,
+    /// Blocks already scanned when the scan started, not counted for the ETA.
+    scanned_at_start: u64,
+    /// Whole percentage last reported.
+    reported: Option<u64>,
+    /// Peers that completed a chunk, and may still report their rescan as
+    /// stopped.
+    finishing: HashSet<PeerId>,
+}
+
+impl Scan {
+    pub fn new(chunk_size: u64, now: Instant) -> Self {
+        Self::resume(Vec::new(), chunk_size, now)
+    }
+
+    /// Resume a scan from its stored chunks.
+    pub fn resume(mut chunks: Vec<Chunk>, chunk_size: u64, now: Instant) -> Self {
+        chunks.sort_by_key(|c| c.begin);
+        let mut scan = Self {
+            chunks,
+            chunk_size: chunk_size.max(1),
+            started_at: now,
+            scanned_at_start: 0,
+            reported: None,
+            finishing: HashSet::new(),
+        };
+        scan.scanned_at_start = scan.scanned();
+        scan
+    }
+
+    /// Chunks of the scan, by height.
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Add the heights of `range` not already part of the scan.
+    ///
+    /// Returns `false` if they all were.
+    pub fn push(&mut self, range: &MerkleScanRange) -> bool {
+        let mut gaps = Vec::new();
+        let mut next = range.begin;
+        for chunk in &self.chunks {
+            if next > range.end || chunk.begin > range.end {
+                break;
+            }
+            if chunk.end < next {
+                continue;
+            }
+            if chunk.begin > next {
+                gaps.push((next, chunk.begin - 1));
+            }
+            next = chunk.end + 1;
+        }
+        if next <= range.end {
+            gaps.push((next, range.end));
+        }
+        for (mut begin, end) in gaps.iter().copied() {
+            while begin <= end {
+                let last = end.min(begin.saturating_add(self.chunk_size - 1));
+                self.chunks.push(Chunk::new(begin, last));
+                begin = last + 1;
+            }
+        }
+        self.chunks.sort_by_key(|c| c.begin);
+
+        !gaps.is_empty()
+    }
+
+    /// Hand the first chunk nobody scans to `peer`.
+    pub fn assign(&mut self, peer: PeerId, now: Instant) -> Option<MerkleScanRange> {
+        let chunk = self
+            .chunks
+            .iter_mut()
+            .find(|c| !c.done && c.peer.is_none())?;
+        chunk.peer = Some(peer);
+        chunk.received = None;
+        chunk.active_at = Some(now);
+
+        Some(chunk.range())
+    }
+
+    /// Whether `peer` is scanning a chunk.
+    pub fn is_scanning(&self, peer: &PeerId) -> bool {
+        self.chunks
+            .iter()
+            .any(|c| !c.done && c.peer.as_ref() == Some(peer))
+    }
+
+    /// Record a merkle block received from `peer`.
+    ///
+    /// Returns the chunk it completed, if any.
+    pub fn received(
+        &mut self,
+        peer: &PeerId,
+        height: u64,
+        now: Instant,
+    ) -> Option<MerkleScanRange> {
+        let chunk = self.chunks.iter_mut().find(|c| {
+            !c.done && c.peer.as_ref() == Some(peer) && (c.begin..=c.end).contains(&height)
+        })?;
+        chunk.received = chunk.received.max(Some(height));
+        chunk.active_at = Some(now);
+        if height < chunk.end {
+            return None;
+        }
+        chunk.done = true;
+        chunk.peer = None;
+        chunk.active_at = None;
+        self.finishing.insert(*peer);
+
+        Some(chunk.range())
+    }
+
+    /// Take back the chunk `peer` was scanning, so that it is scanned again.
+    pub fn release(&mut self, peer: &PeerId) -> Option<MerkleScanRange> {
+        self.finishing.remove(peer);
+        let chunk = self
+            .chunks
+            .iter_mut()
+            .find(|c| !c.done && c.peer.as_ref() == Some(peer))?;
+
+        Some(chunk.release())
+    }
+
+    /// Take back the chunk `peer` stopped scanning before its end.
+    ///
+    /// The first stop following a completed chunk is the end of that chunk's
+    /// rescan, and leaves the chunk handed out since alone.
+    pub fn interrupted(&mut self, peer: &PeerId) -> Option<MerkleScanRange> {
+        if self.finishing.remove(peer) {
+            return None;
+        }
+        self.release(peer)
+    }
+
+    /// Take back the chunks whose peer sent no block for [`STALL_TIMEOUT`].
+    ///
+    /// Returns them along with the peer that was scanning them.
+    pub fn stalled(&mut self, now: Instant) -> Vec<(PeerId, MerkleScanRange)> {
+        self.chunks
+            .iter_mut()
+            .filter(|c| !c.done)
+            .filter_map(|c| {
+                let peer = c.peer?;
+                let idle = now.saturating_duration_since(c.active_at?);
+                (idle >= STALL_TIMEOUT).then(|| (peer, c.release()))
+            })
+            .collect()
+    }
+
+    /// Whether every height of `range` was scanned.
+    pub fn covers(&self, range: &MerkleScanRange) -> bool {
+        let mut next = range.begin;
+        for chunk in self.chunks.iter().filter(|c| c.end >= range.begin) {
+            if next > range.end {
+                break;
+            }
+            if chunk.begin > next || !chunk.done {
+                return false;
+            }
+            next = chunk.end + 1;
+        }
+        next > range.end
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.chunks.iter().all(|c| c.done)
+    }
+
+    fn scanned(&self) -> u64 {
+        self.chunks.iter().map(Chunk::scanned).sum()
+    }
+
+    pub fn progress(&self, now: Instant) -> ScanProgress {
+        let scanned = self.scanned();
+        let total = self.chunks.iter().map(|c| c.range().len()).sum();
+        let progressed = scanned.saturating_sub(self.scanned_at_start);
+        let eta = (progressed > 0).then(|| {
+            let elapsed = now.saturating_duration_since(self.started_at);
+            elapsed.mul_f64((total - scanned) as f64 / progressed as f64)
+        });
+        let mut ranges = Vec::<MerkleScanRange>::new();
+        for chunk in &self.chunks {
+            match ranges.last_mut() {
+                Some(last) if last.end + 1 == chunk.begin => last.end = chunk.end,
+                _ => ranges.push(chunk.range()),
+            }
+        }
+
+        ScanProgress {
+            scanned,
+            total,
+            eta,
+            ranges,
+        }
+    }
+
+    /// Progress, if the whole percentage changed since it was last reported.
+    pub fn report(&mut self, now: Instant) -> Option<ScanProgress> {
+        let progress = self.progress(now);
+        let percent = progress.percent() as u64;
+        if self.reported == Some(percent) {
+            return None;
+        }
+        self.reported = Some(percent);
+
+        Some(progress)
+    }
+}
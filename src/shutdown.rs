@@ -0,0 +1,60 @@
+//! Coordinated shutdown of the client, watcher and front-end threads.
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{self as chan, Receiver, Sender, TryRecvError};
+
+use crate::error::Error;
+
+/// A shutdown signal shared by every thread.
+///
+/// Triggering any clone wakes up everyone waiting on [`Shutdown::receiver`]:
+/// nothing is ever sent on the channel, it just disconnects.
+#[derive(Clone)]
+pub struct Shutdown {
+    trigger: Arc<Mutex<Option<Sender<()>>>>,
+    receiver: Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (trigger, receiver) = chan::bounded(0);
+        Self {
+            trigger: Arc::new(Mutex::new(Some(trigger))),
+            receiver,
+        }
+    }
+
+    /// Ask every thread to stop. Triggering more than once is harmless.
+    pub fn trigger(&self) {
+        let trigger = match self.trigger.lock() {
+            Ok(mut trigger) => trigger.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if trigger.is_some() {
+            log::info!("Shutting down");
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        matches!(self.receiver.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    /// A channel that becomes ready, with an error, once shutdown is triggered.
+    /// Meant for `select!`.
+    pub fn receiver(&self) -> &Receiver<()> {
+        &self.receiver
+    }
+
+    /// Trigger on SIGINT and SIGTERM.
+    pub fn on_signals(&self) -> Result<(), Error> {
+        let shutdown = self.clone();
+        ctrlc::set_handler(move || shutdown.trigger())
+            .map_err(|e| Error::Signal(e.to_string()))
+    }
+}
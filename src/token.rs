@@ -0,0 +1,251 @@
+//! CashTokens carried by transaction outputs.
+//!
+//! A token output prefixes its locking bytecode, inside the `scriptPubKey`
+//! field, with `PREFIX_TOKEN` (`0xef`) followed by the token data:
+//!
+//! | bytes | field                                                    |
+//! |-------|----------------------------------------------------------|
+//! | 32    | category, the txid of the genesis transaction's input 0  |
+//! | 1     | bitfield: flags in the high nibble, capability in the low |
+//! | ..    | commitment length and commitment, if flagged             |
+//! | ..    | fungible amount as a compact size, if flagged             |
+use std::fmt;
+use std::str::FromStr;
+
+use nakamoto_cash::common::bitcoin::{Script, TxOut};
+
+use crate::error::Error;
+
+pub const PREFIX_TOKEN: u8 = 0xef;
+
+/// Prefix of watch items naming a token category, eg. `token:<category>`.
+pub const ITEM_PREFIX: &str = "token:";
+
+const CATEGORY_LEN: usize = 32;
+const MAX_COMMITMENT_LEN: usize = 40;
+const MAX_AMOUNT: u64 = i64::MAX as u64;
+
+const RESERVED: u8 = 0x10;
+const HAS_AMOUNT: u8 = 0x20;
+const HAS_NFT: u8 = 0x40;
+const HAS_COMMITMENT_LENGTH: u8 = 0x80;
+
+/// Token category ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Category(pub [u8; CATEGORY_LEN]);
+
+impl Category {
+    /// The category as it appears in scripts and bloom filters.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Category {
+    /// Like txids, categories are displayed in reverse byte order.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = self.0;
+        bytes.reverse();
+        write!(f, "{}", hex::encode(bytes))
+    }
+}
+
+impl FromStr for Category {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes: [u8; CATEGORY_LEN] = hex::decode(s)
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| Error::Token(format!("bad category {:?}", s)))?;
+        bytes.reverse();
+        Ok(Self(bytes))
+    }
+}
+
+/// What the holder of a non-fungible token may do with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// The commitment can't be changed.
+    None,
+    /// The commitment can be changed once, when spending the token.
+    Mutable,
+    /// New tokens of the category can be created.
+    Minting,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "immutable"),
+            Self::Mutable => write!(f, "mutable"),
+            Self::Minting => write!(f, "minting"),
+        }
+    }
+}
+
+/// A non-fungible token.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Nft {
+    pub capability: Capability,
+    pub commitment: Vec<u8>,
+}
+
+/// Tokens held by an output.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TokenData {
+    pub category: Category,
+    /// Fungible amount, zero if the output only holds an NFT.
+    pub amount: u64,
+    pub nft: Option<Nft>,
+}
+
+impl fmt::Display for TokenData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.category)?;
+        if self.amount > 0 {
+            write!(f, " amount {}", self.amount)?;
+        }
+        if let Some(nft) = &self.nft {
+            write!(f, " nft {}", nft.capability)?;
+            if !nft.commitment.is_empty() {
+                write!(f, " {}", hex::encode(&nft.commitment))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TokenData {
+    /// Split an output's `scriptPubKey` field into its token data, if any,
+    /// and the actual locking script.
+    pub fn split(script: &Script) -> Result<(Option<Self>, Script), Error> {
+        match script.as_bytes() {
+            [PREFIX_TOKEN, rest @ ..] => {
+                let mut reader = Reader(rest);
+                let token = Self::decode(&mut reader)?;
+                Ok((Some(token), Script::from(reader.0.to_vec())))
+            }
+            _ => Ok((None, script.clone())),
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, Error> {
+        let category = Category(reader.take(CATEGORY_LEN)?.try_into().expect("category length"));
+        let bitfield = reader.byte()?;
+        let (flags, capability) = (bitfield & 0xf0, bitfield & 0x0f);
+
+        if flags & RESERVED != 0 {
+            return Err(Error::Token("reserved bit set".to_owned()));
+        }
+        if flags & (HAS_AMOUNT | HAS_NFT) == 0 {
+            return Err(Error::Token("no tokens".to_owned()));
+        }
+        if flags & HAS_NFT == 0 && (flags & HAS_COMMITMENT_LENGTH != 0 || capability != 0) {
+            return Err(Error::Token("commitment or capability without an NFT".to_owned()));
+        }
+        let capability = match capability {
+            0 => Capability::None,
+            1 => Capability::Mutable,
+            2 => Capability::Minting,
+            c => return Err(Error::Token(format!("unknown capability {}", c))),
+        };
+        let commitment = if flags & HAS_COMMITMENT_LENGTH != 0 {
+            let len = reader.compact_size()? as usize;
+            if len == 0 || len > MAX_COMMITMENT_LEN {
+                return Err(Error::Token(format!("commitment length {}", len)));
+            }
+            reader.take(len)?.to_vec()
+        } else {
+            Vec::new()
+        };
+        let amount = if flags & HAS_AMOUNT != 0 {
+            let amount = reader.compact_size()?;
+            if amount == 0 || amount > MAX_AMOUNT {
+                return Err(Error::Token(format!("amount {}", amount)));
+            }
+            amount
+        } else {
+            0
+        };
+        let nft = (flags & HAS_NFT != 0).then_some(Nft {
+            capability,
+            commitment,
+        });
+
+        Ok(Self {
+            category,
+            amount,
+            nft,
+        })
+    }
+}
+
+/// The category named by a watch item, `None` if the item isn't one.
+pub fn parse_item(data: &str) -> Option<Result<Category, Error>> {
+    data.trim().strip_prefix(ITEM_PREFIX).map(Category::from_str)
+}
+
+/// Token data and locking script of `output`.
+///
+/// An output with a malformed prefix is invalid and can't be mined, it is
+/// treated as holding no tokens.
+pub fn split_output(output: &TxOut) -> (Option<TokenData>, Script) {
+    TokenData::split(&output.script_pubkey).unwrap_or_else(|e| {
+        log::debug!("Ignoring {}", e);
+        (None, output.script_pubkey.clone())
+    })
+}
+
+/// Tokens of one category held by a script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenBalance {
+    pub category: Category,
+    pub amount: u64,
+    pub nfts: Vec<Nft>,
+}
+
+impl fmt::Display for TokenBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {}", self.amount, self.category)?;
+        if !self.nfts.is_empty() {
+            write!(f, " and {} nft(s)", self.nfts.len())?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(Error::Token("truncated".to_owned()));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// A minimally encoded compact size.
+    fn compact_size(&mut self) -> Result<u64, Error> {
+        let (n, min) = match self.byte()? {
+            0xfd => (u16::from_le_bytes(self.array()?) as u64, 0xfd),
+            0xfe => (u32::from_le_bytes(self.array()?) as u64, 0x1_0000),
+            0xff => (u64::from_le_bytes(self.array()?), 0x1_0000_0000),
+            n => return Ok(n as u64),
+        };
+        if n < min {
+            return Err(Error::Token("non-minimal compact size".to_owned()));
+        }
+        Ok(n)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().expect("slice length"))
+    }
+}
@@ -0,0 +1,214 @@
+//! Unspent outputs and balances of watched scripts.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use nakamoto_cash::chain::Transaction;
+use nakamoto_cash::common::bitcoin::{OutPoint, Script, Txid};
+
+use crate::token::{self, TokenBalance, TokenData};
+
+/// An unspent output paying to a watched script.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    /// Locking script, without the token prefix.
+    pub script_pubkey: Script,
+    /// CashTokens held by the output.
+    pub token: Option<TokenData>,
+    /// Height of the confirming block, `None` while in the mempool.
+    pub height: Option<u64>,
+}
+
+/// Balance of a script, in satoshis.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Balance {
+    pub confirmed: u64,
+    pub unconfirmed: u64,
+}
+
+impl Balance {
+    pub fn total(&self) -> u64 {
+        self.confirmed + self.unconfirmed
+    }
+}
+
+/// Unspent outputs of watched scripts, fed by matched transactions and merkle blocks.
+#[derive(Clone, Debug, Default)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, Utxo>,
+    /// Outpoints seen spent, in case the spend arrives before the output.
+    /// Dropped once the output is seen.
+    spent: HashSet<OutPoint>,
+    /// Outpoints spent by transactions we broadcast, by txid, until the
+    /// spend is seen or given up.
+    reserved: HashMap<OutPoint, Txid>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a matched transaction, confirmed at `height` if it was proven:
+    /// spend the outputs it consumes, and add its outputs paying to scripts
+    /// for which `is_watched` holds.
+    ///
+    /// Returns the scripts whose balance changed.
+    pub fn apply(
+        &mut self,
+        tx: &Transaction,
+        height: Option<u64>,
+        is_watched: impl Fn(&Script) -> bool,
+    ) -> HashSet<Script> {
+        let txid = tx.txid();
+        let mut changed = HashSet::new();
+
+        for input in &tx.input {
+            self.reserved.remove(&input.previous_output);
+            match self.utxos.remove(&input.previous_output) {
+                Some(utxo) => {
+                    changed.insert(utxo.script_pubkey);
+                }
+                None => {
+                    self.spent.insert(input.previous_output);
+                }
+            }
+        }
+        for (vout, output) in tx.output.iter().enumerate() {
+            let outpoint = OutPoint::new(txid, vout as u32);
+            // The output is resolved, whether it is ours or not.
+            if self.spent.remove(&outpoint) {
+                continue;
+            }
+            let (token, script_pubkey) = token::split_output(output);
+            if !is_watched(&script_pubkey) {
+                continue;
+            }
+            let utxo = Utxo {
+                outpoint,
+                value: output.value,
+                script_pubkey: script_pubkey.clone(),
+                token,
+                height,
+            };
+            if self.utxos.insert(outpoint, utxo).is_none() {
+                changed.insert(script_pubkey);
+            }
+        }
+        changed
+    }
+
+    /// Mark the outputs of a transaction as confirmed at `height`.
+    ///
+    /// Returns the scripts whose balance changed.
+    pub fn confirm(&mut self, txid: Txid, height: u64) -> HashSet<Script> {
+        self.utxos
+            .values_mut()
+            .filter(|u| u.outpoint.txid == txid && u.height != Some(height))
+            .map(|u| {
+                u.height = Some(height);
+                u.script_pubkey.clone()
+            })
+            .collect()
+    }
+
+    /// Mark a transaction as unconfirmed again, after a reorg.
+    ///
+    /// Returns the scripts whose balance changed.
+    pub fn unconfirm(&mut self, txid: Txid) -> HashSet<Script> {
+        self.utxos
+            .values_mut()
+            .filter(|u| u.outpoint.txid == txid && u.height.is_some())
+            .map(|u| {
+                u.height = None;
+                u.script_pubkey.clone()
+            })
+            .collect()
+    }
+
+    /// Unspent outputs of all watched scripts.
+    pub fn utxos(&self) -> impl Iterator<Item = &Utxo> {
+        self.utxos.values()
+    }
+
+    /// Unspent outputs that no transaction we broadcast spends.
+    pub fn spendable(&self) -> impl Iterator<Item = &Utxo> {
+        self.utxos
+            .values()
+            .filter(|u| !self.reserved.contains_key(&u.outpoint))
+    }
+
+    /// Reserve the outputs spent by `tx`, which we are broadcasting, so that
+    /// they aren't spent again until the spend is seen or given up.
+    pub fn reserve(&mut self, tx: &Transaction) {
+        let txid = tx.txid();
+        for input in &tx.input {
+            if self.utxos.contains_key(&input.previous_output) {
+                self.reserved.insert(input.previous_output, txid);
+            }
+        }
+    }
+
+    /// Make the outputs reserved by `txid` spendable again.
+    ///
+    /// Returns `false` if it reserved none.
+    pub fn release(&mut self, txid: Txid) -> bool {
+        let len = self.reserved.len();
+        self.reserved.retain(|_, t| *t != txid);
+        self.reserved.len() != len
+    }
+
+    /// Whether a transaction we broadcast spends `outpoint`.
+    pub fn is_reserved(&self, outpoint: &OutPoint) -> bool {
+        self.reserved.contains_key(outpoint)
+    }
+
+    /// Balance of a single script.
+    pub fn balance(&self, script: &Script) -> Balance {
+        self.utxos
+            .values()
+            .filter(|u| &u.script_pubkey == script)
+            .fold(Balance::default(), add)
+    }
+
+    /// Tokens held by a single script, by category.
+    pub fn tokens(&self, script: &Script) -> Vec<TokenBalance> {
+        let mut tokens = BTreeMap::new();
+
+        for token in self
+            .utxos
+            .values()
+            .filter(|u| &u.script_pubkey == script)
+            .filter_map(|u| u.token.as_ref())
+        {
+            let balance = tokens.entry(token.category).or_insert_with(|| TokenBalance {
+                category: token.category,
+                amount: 0,
+                nfts: Vec::new(),
+            });
+            // Amounts of unproven transactions can't be trusted not to overflow.
+            balance.amount = balance.amount.saturating_add(token.amount);
+            balance.nfts.extend(token.nft.clone());
+        }
+        tokens.into_values().collect()
+    }
+
+    /// Balance of all watched scripts.
+    pub fn total(&self) -> Balance {
+        self.utxos.values().fold(Balance::default(), add)
+    }
+
+    pub fn clear(&mut self) {
+        self.utxos.clear();
+        self.spent.clear();
+        self.reserved.clear();
+    }
+}
+
+fn add(mut balance: Balance, utxo: &Utxo) -> Balance {
+    match utxo.height {
+        Some(_) => balance.confirmed += utxo.value,
+        None => balance.unconfirmed += utxo.value,
+    }
+    balance
+}
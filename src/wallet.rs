@@ -0,0 +1,539 @@
+//! Signing wallet: named wallet files holding an HD key, P2PKH addresses,
+//! coin selection and BCH transaction signing.
+//!
+//! Keys are derived at `m/44'/145'/0'/0/i` for receive addresses and
+//! `m/44'/145'/0'/1/i` for change. Inputs are signed with
+//! `SIGHASH_ALL | SIGHASH_FORKID`, as required by Bitcoin Cash.
+//!
+//! Wallet files are encrypted with a password, see [`crate::keystore`].
+//! Plain JSON files, as written by earlier versions, are still read, and stay
+//! unencrypted until [`Wallet::set_password`] is called.
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write as _};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use nakamoto_cash::chain::Transaction;
+use nakamoto_cash::client::Network;
+use nakamoto_cash::common::bitcoin::blockdata::script::Builder;
+use nakamoto_cash::common::bitcoin::consensus::encode::serialize;
+use nakamoto_cash::common::bitcoin::hashes::{Hash, hash160, sha256d};
+use nakamoto_cash::common::bitcoin::secp256k1::{All, Message, PublicKey, Secp256k1, SecretKey};
+use nakamoto_cash::common::bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey};
+use nakamoto_cash::common::bitcoin::{self, PackedLockTime, Script, Sequence, TxIn, TxOut, Witness};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::address::{self, Address, AddressType};
+use crate::error::Error;
+use crate::hd::{self, Chain};
+use crate::keystore::Keystore;
+use crate::utxo::Utxo;
+
+/// Outputs below this value are not relayed.
+pub const DUST_LIMIT: u64 = 546;
+
+/// Default fee rate, in satoshis per byte.
+pub const DEFAULT_FEE_RATE: u64 = 1;
+
+/// `SIGHASH_ALL | SIGHASH_FORKID`, the only hash type inputs are signed with.
+pub const SIGHASH_ALL_FORKID: u32 = 0x41;
+const FILE_VERSION: u32 = 1;
+const EXTENSION: &str = "wallet";
+/// Extension of unencrypted wallet files written by earlier versions.
+const LEGACY_EXTENSION: &str = "json";
+
+/// Estimated sizes, in bytes, of a transaction's parts.
+const TX_OVERHEAD: u64 = 10;
+const P2PKH_INPUT_SIZE: u64 = 148;
+const P2PKH_OUTPUT_SIZE: u64 = 34;
+
+/// On-disk representation of a wallet.
+#[derive(Serialize, Deserialize)]
+struct WalletFile {
+    version: u32,
+    network: String,
+    /// Master extended private key.
+    xpriv: String,
+    /// Number of receive addresses handed out.
+    receive: u32,
+    /// Number of change addresses used.
+    change: u32,
+}
+
+/// A named wallet, stored as `<name>.wallet` in the wallets directory.
+pub struct Wallet {
+    name: String,
+    path: PathBuf,
+    network: Network,
+    master: ExtendedPrivKey,
+    /// Account key, at `m/44'/145'/0'`.
+    account: ExtendedPrivKey,
+    receive: u32,
+    change: u32,
+    keys: HashMap<Script, SecretKey>,
+    secp: Secp256k1<All>,
+    /// Key the file is encrypted with, `None` for plain files.
+    keystore: Option<Keystore>,
+}
+
+impl Wallet {
+    /// Directory holding the wallets of `network`, under the client `root`.
+    pub fn dir(root: &Path, network: Network) -> PathBuf {
+        root.join(".nakamoto").join(network.as_str()).join("wallets")
+    }
+
+    /// Names of the wallets in `dir`.
+    pub fn list(dir: &Path) -> Result<Vec<String>, Error> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Loading(e.kind())),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| Error::Loading(e.kind()))?.path();
+            if path
+                .extension()
+                .is_some_and(|e| e == EXTENSION || e == LEGACY_EXTENSION)
+            {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Create a new wallet from a random seed, and save it to `dir`, encrypted
+    /// with `password`.
+    pub fn create(dir: &Path, name: &str, network: Network, password: &str) -> Result<Self, Error> {
+        let path = Self::file_path(dir, name)?;
+        if path.exists() {
+            return Err(Error::Loading(io::ErrorKind::AlreadyExists));
+        }
+        let mut seed = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut seed);
+        let master = ExtendedPrivKey::new_master(key_network(network), &seed)
+            .map_err(|_| Error::InvalidScalarBytes)?;
+
+        let mut wallet = Self::new(name, path, network, master, 0, 0)?;
+        wallet.keystore = Some(Keystore::new(password)?);
+        wallet.save()?;
+
+        Ok(wallet)
+    }
+
+    /// Path of the wallet called `name` in `dir`.
+    pub fn file_path(dir: &Path, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.contains(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_') {
+            return Err(Error::WalletNameNotFound);
+        }
+        let path = dir.join(name).with_extension(EXTENSION);
+        let legacy = path.with_extension(LEGACY_EXTENSION);
+        if !path.exists() && legacy.exists() {
+            return Ok(legacy);
+        }
+        Ok(path)
+    }
+
+    /// Whether the wallet file at `path` is encrypted, ie. needs a password.
+    pub fn is_encrypted(path: &Path) -> Result<bool, Error> {
+        Ok(Keystore::is_sealed(&read(path)?))
+    }
+
+    /// Load the wallet called `name` from `dir`.
+    pub fn load(
+        dir: &Path,
+        name: &str,
+        network: Network,
+        password: Option<&str>,
+    ) -> Result<Self, Error> {
+        Self::open(&Self::file_path(dir, name)?, network, password)
+    }
+
+    /// Load the wallet file at `path`. A `password` is needed if the file is
+    /// encrypted.
+    pub fn open(path: &Path, network: Network, password: Option<&str>) -> Result<Self, Error> {
+        let data = read(path)?;
+        let (plaintext, keystore) = if Keystore::is_sealed(&data) {
+            let password = password
+                .ok_or_else(|| Error::ReadWallet("wallet is encrypted, a password is needed".to_owned()))?;
+            let (plaintext, keystore) = Keystore::open(password, &data)?;
+            (plaintext, Some(keystore))
+        } else {
+            (data, None)
+        };
+        let file: WalletFile = serde_json::from_slice(&plaintext)
+            .map_err(|e| Error::ReadWallet(format!("corrupt wallet file: {}", e)))?;
+
+        if file.version != FILE_VERSION {
+            return Err(Error::ReadWallet(format!("unsupported version {}", file.version)));
+        }
+        if file.network != network.as_str() {
+            return Err(Error::ReadWallet(format!("wallet is for {}", file.network)));
+        }
+        let master = ExtendedPrivKey::from_str(&file.xpriv)
+            .map_err(|e| Error::ReadWallet(e.to_string()))?;
+
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or(Error::WalletNameNotFound)?;
+        let mut wallet = Self::new(name, path.to_owned(), network, master, file.receive, file.change)?;
+        wallet.keystore = keystore;
+
+        Ok(wallet)
+    }
+
+    fn new(
+        name: &str,
+        path: PathBuf,
+        network: Network,
+        master: ExtendedPrivKey,
+        receive: u32,
+        change: u32,
+    ) -> Result<Self, Error> {
+        let secp = Secp256k1::new();
+        let account_path = DerivationPath::from_str(hd::DEFAULT_PATH).map_err(|_| Error::XprivPath)?;
+        let account = master
+            .derive_priv(&secp, &account_path)
+            .map_err(|_| Error::XprivPath)?;
+
+        let mut wallet = Self {
+            name: name.to_owned(),
+            path,
+            network,
+            master,
+            account,
+            receive: 0,
+            change: 0,
+            keys: HashMap::new(),
+            secp,
+            keystore: None,
+        };
+        // Always have a receive address to hand out.
+        while wallet.receive < receive.max(1) {
+            wallet.derive(Chain::Receive)?;
+        }
+        while wallet.change < change {
+            wallet.derive(Chain::Change)?;
+        }
+        Ok(wallet)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Encrypt the wallet with `password` from now on, and save it.
+    pub fn set_password(&mut self, password: &str) -> Result<(), Error> {
+        self.keystore = Some(Keystore::new(password)?);
+
+        let legacy = self.path.extension().is_some_and(|e| e == LEGACY_EXTENSION);
+        if !legacy {
+            return self.save();
+        }
+        let old = std::mem::replace(&mut self.path, self.path.with_extension(EXTENSION));
+        self.save()?;
+        fs::remove_file(old)?;
+
+        Ok(())
+    }
+
+    /// Write the wallet to disk.
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = WalletFile {
+            version: FILE_VERSION,
+            network: self.network.as_str().to_owned(),
+            xpriv: self.master.to_string(),
+            receive: self.receive,
+            change: self.change,
+        };
+        let json = serde_json::to_vec_pretty(&file).map_err(|_| Error::WriteError)?;
+        let data = match &self.keystore {
+            Some(keystore) => keystore.seal(&json)?,
+            None => json,
+        };
+        // Write to a temporary file first, so that a crash never loses the keys.
+        let tmp = self.path.with_extension("tmp");
+        write_private(&tmp, &data)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+
+    /// Extended public key of the account, to watch the wallet elsewhere.
+    pub fn xpub(&self) -> String {
+        bitcoin::util::bip32::ExtendedPubKey::from_priv(&self.secp, &self.account).to_string()
+    }
+
+    /// All addresses handed out so far, receive and change.
+    pub fn addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        let prefix = address::prefix(self.network);
+        self.keys
+            .keys()
+            .filter_map(move |script| Address::from_script(prefix, script))
+    }
+
+    /// Latest receive address.
+    pub fn receive_address(&self) -> Result<Address, Error> {
+        Ok(self.address_of(&self.key(Chain::Receive, self.receive - 1)?))
+    }
+
+    /// Hand out a new receive address, and save the wallet.
+    pub fn new_address(&mut self) -> Result<Address, Error> {
+        let address = self.derive(Chain::Receive)?;
+        self.save()?;
+
+        Ok(address)
+    }
+
+    /// Whether the wallet can spend outputs locked by `script`.
+    pub fn is_mine(&self, script: &Script) -> bool {
+        self.keys.contains_key(script)
+    }
+
+    /// Pay `outputs` from `utxos`, sending change to a new change address.
+    ///
+    /// Confirmed coins are spent first, largest first. Coins holding tokens are
+    /// left alone, as spending them here would burn the tokens. The change
+    /// address is derived and saved before the transaction is returned.
+    pub fn build_transaction<'a>(
+        &mut self,
+        utxos: impl IntoIterator<Item = &'a Utxo>,
+        outputs: &[(Address, u64)],
+        fee_rate: u64,
+    ) -> Result<Transaction, Error> {
+        if outputs.iter().any(|(_, value)| *value < DUST_LIMIT) {
+            return Err(Error::Dust);
+        }
+        let amount = outputs.iter().map(|(_, value)| value).sum::<u64>();
+
+        let mut coins = utxos
+            .into_iter()
+            .filter(|u| u.token.is_none() && self.is_mine(&u.script_pubkey))
+            .collect::<Vec<_>>();
+        coins.sort_by_key(|u| (u.height.is_none(), std::cmp::Reverse(u.value)));
+
+        let mut selected = Vec::new();
+        let mut total = 0;
+        // Fee of the transaction with a change output.
+        let fee = |inputs: usize| {
+            fee_rate
+                * (TX_OVERHEAD
+                    + P2PKH_INPUT_SIZE * inputs as u64
+                    + P2PKH_OUTPUT_SIZE * (outputs.len() as u64 + 1))
+        };
+        for coin in coins {
+            if total >= amount + fee(selected.len()) {
+                break;
+            }
+            total += coin.value;
+            selected.push(coin);
+        }
+        let fee = fee(selected.len());
+        if total < amount + fee {
+            return Err(Error::InsufficientFunds {
+                needed: amount + fee,
+                available: total,
+            });
+        }
+
+        let mut output = outputs
+            .iter()
+            .map(|(address, value)| TxOut {
+                value: *value,
+                script_pubkey: address.script_pubkey(),
+            })
+            .collect::<Vec<_>>();
+        let change = total - amount - fee;
+        // Change below the dust limit is left to the miners.
+        if change >= DUST_LIMIT {
+            let address = self.derive(Chain::Change)?;
+            self.save()?;
+            output.push(TxOut {
+                value: change,
+                script_pubkey: address.script_pubkey(),
+            });
+        }
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: selected
+                .iter()
+                .map(|u| TxIn {
+                    previous_output: u.outpoint,
+                    script_sig: Script::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output,
+        };
+        self.sign(&mut tx, &selected)?;
+
+        Ok(tx)
+    }
+
+    /// Sign every input of `tx`, spending `coins` in the same order.
+    fn sign(&self, tx: &mut Transaction, coins: &[&Utxo]) -> Result<(), Error> {
+        let mut script_sigs = Vec::with_capacity(coins.len());
+
+        for (index, coin) in coins.iter().enumerate() {
+            if address::parse_script(coin.script_pubkey.as_bytes())
+                .is_none_or(|(kind, _)| kind != AddressType::P2pkh)
+            {
+                return Err(Error::Script);
+            }
+            let key = self.keys.get(&coin.script_pubkey).ok_or(Error::ScriptNotFound)?;
+            let digest =
+                signature_hash(tx, index, &coin.script_pubkey, coin.value, SIGHASH_ALL_FORKID);
+            let message = Message::from_slice(&digest).map_err(|_| Error::InvalidScalarBytes)?;
+
+            let mut signature = self.secp.sign_ecdsa(&message, key).serialize_der().to_vec();
+            signature.push(SIGHASH_ALL_FORKID as u8);
+            let public_key = PublicKey::from_secret_key(&self.secp, key);
+
+            script_sigs.push(
+                Builder::new()
+                    .push_slice(&signature)
+                    .push_slice(&public_key.serialize())
+                    .into_script(),
+            );
+        }
+        for (input, script_sig) in tx.input.iter_mut().zip(script_sigs) {
+            input.script_sig = script_sig;
+        }
+        Ok(())
+    }
+
+    /// Derive the next key of `chain`.
+    fn derive(&mut self, chain: Chain) -> Result<Address, Error> {
+        let index = match chain {
+            Chain::Receive => self.receive,
+            Chain::Change => self.change,
+        };
+        let key = self.key(chain, index)?;
+        let address = self.address_of(&key);
+        self.keys.insert(address.script_pubkey(), key);
+
+        match chain {
+            Chain::Receive => self.receive += 1,
+            Chain::Change => self.change += 1,
+        }
+        Ok(address)
+    }
+
+    fn key(&self, chain: Chain, index: u32) -> Result<SecretKey, Error> {
+        let branch = match chain {
+            Chain::Receive => 0,
+            Chain::Change => 1,
+        };
+        let path = [
+            ChildNumber::from_normal_idx(branch).map_err(|_| Error::XprivPath)?,
+            ChildNumber::from_normal_idx(index).map_err(|_| Error::XprivPath)?,
+        ];
+        let key = self
+            .account
+            .derive_priv(&self.secp, &path)
+            .map_err(|_| Error::XprivPath)?;
+
+        Ok(key.private_key)
+    }
+
+    fn address_of(&self, key: &SecretKey) -> Address {
+        let public_key = PublicKey::from_secret_key(&self.secp, key);
+
+        Address {
+            prefix: address::prefix(self.network).to_owned(),
+            kind: AddressType::P2pkh,
+            token_aware: false,
+            hash: hash160::Hash::hash(&public_key.serialize()).into_inner().to_vec(),
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(data),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::WalletNameNotFound),
+        Err(e) => Err(Error::Loading(e.kind())),
+    }
+}
+
+/// Write `data` to a new file at `path`, created readable by its owner only
+/// so that the keys are never exposed, even briefly.
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    // A file left over by a crash may have been created with other permissions.
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// BIP143 digest of input `index` of `tx`, spending `value` satoshis locked by
+/// `script_code`. Bitcoin Cash uses it with [`SIGHASH_ALL_FORKID`].
+pub fn signature_hash(
+    tx: &Transaction,
+    index: usize,
+    script_code: &Script,
+    value: u64,
+    hash_type: u32,
+) -> [u8; 32] {
+    let mut prevouts = Vec::new();
+    let mut sequences = Vec::new();
+    for input in &tx.input {
+        prevouts.extend(serialize(&input.previous_output));
+        sequences.extend(input.sequence.0.to_le_bytes());
+    }
+    let mut outputs = Vec::new();
+    for output in &tx.output {
+        outputs.extend(serialize(output));
+    }
+    let input = &tx.input[index];
+
+    let mut preimage = Vec::new();
+    preimage.extend(tx.version.to_le_bytes());
+    preimage.extend(sha256d::Hash::hash(&prevouts).into_inner());
+    preimage.extend(sha256d::Hash::hash(&sequences).into_inner());
+    preimage.extend(serialize(&input.previous_output));
+    preimage.extend(serialize(script_code));
+    preimage.extend(value.to_le_bytes());
+    preimage.extend(input.sequence.0.to_le_bytes());
+    preimage.extend(sha256d::Hash::hash(&outputs).into_inner());
+    preimage.extend(tx.lock_time.0.to_le_bytes());
+    preimage.extend(hash_type.to_le_bytes());
+
+    sha256d::Hash::hash(&preimage).into_inner()
+}
+
+/// Network of extended keys, which only affects their encoding.
+fn key_network(network: Network) -> bitcoin::Network {
+    match network {
+        Network::Mainnet => bitcoin::Network::Bitcoin,
+        _ => bitcoin::Network::Testnet,
+    }
+}
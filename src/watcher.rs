@@ -0,0 +1,1626 @@
+//! Watch-only logic shared by the headless client and the UI example.
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{self as chan, Receiver, Sender};
+use nakamoto_cash::chain::Transaction;
+use nakamoto_cash::client::traits::Handle;
+use nakamoto_cash::client::{self, Event, Network};
+use nakamoto_cash::common::bitcoin::util::bloom::BloomFilter;
+use nakamoto_cash::common::bitcoin::consensus::encode::serialize;
+use nakamoto_cash::common::bitcoin::{OutPoint, Script, TxOut, Txid};
+use nakamoto_cash::p2p::Command;
+use nakamoto_cash::p2p::PeerId;
+
+use crate::address::{self, Address};
+use crate::broadcast::{self, Broadcast, PeerStatus};
+use crate::error;
+use crate::filter::{self, FilterBuilder};
+use crate::hd::{self, HdAccount};
+use crate::history::{History, TxStatus};
+use crate::merkle::{self, Confirmation};
+use crate::scan::{self, MerkleScanRange, Scan, ScanProgress};
+use crate::shutdown::Shutdown;
+use crate::token::{self, Category, TokenBalance, TokenData};
+use crate::utxo::{Balance, UtxoSet};
+use crate::wallet::Wallet;
+use crate::watchlist::{WatchItem, WatchKind, WatchList};
+
+const TXID_LEN: usize = 32;
+
+/// How often periodic tasks are checked for, see [`Watcher::handle_tick`].
+pub const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the filter is loaded again onto the filtered peers, as peers
+/// updating it with matched outpoints make it less and less selective.
+pub const DEFAULT_FILTER_REFRESH: Duration = Duration::from_secs(10 * 60);
+
+/// Messages exchanged between the [`Watcher`] and its front-end.
+///
+/// Some variants are only ever sent by the front-end (eg. [`UIMessage::AddBloomItem`]),
+/// others only by the watcher (eg. [`UIMessage::ReceivedMatchedTx`]).
+#[derive(Clone, Debug)]
+pub enum UIMessage {
+    /// The client is ready on the given network.
+    NetworkConnected(Network),
+    /// Whether any filtered peer is currently sending merkle blocks.
+    BlocksDownloading(bool),
+    /// Headers are loaded up to this height.
+    HeaderLoaded(u64),
+    /// A new block was connected at this height.
+    BlockConnected(u64),
+    /// Add a cashaddr, hex script, txid or `token:<category>` to the bloom
+    /// filter.
+    AddBloomItem(String),
+    /// Like [`UIMessage::AddBloomItem`], with a label stored in the watch list
+    /// and the height to scan the item's history from, defaults to the tip.
+    AddWatchItem {
+        data: String,
+        label: Option<String>,
+        birthday: Option<u64>,
+    },
+    /// Remove an item added with [`UIMessage::AddBloomItem`].
+    RemoveBloomItem(String),
+    /// Ask for all matched transactions and their status.
+    ListMatchedTxs(Sender<Vec<(Txid, Option<TxStatus>)>>),
+    /// Items restored from the persisted watch list.
+    WatchListLoaded(Vec<WatchItem>),
+    /// Load the bloom filter onto all negotiated peers.
+    SendLoadFilter,
+    ResetFilter,
+    /// Clear the bloom filter and forget which peers were filtered.
+    ClearFilterAndPeers,
+    /// A peer acknowledged our bloom filter.
+    PeerLoadedFilter(PeerId),
+    /// A transaction matching the filter was received, with the block it was
+    /// proven to be in, if any.
+    ReceivedMatchedTx {
+        transaction: Transaction,
+        confirmation: Option<Confirmation>,
+        /// Tokens held by the transaction's outputs, by output index.
+        tokens: Vec<(u32, TokenData)>,
+    },
+    /// The confirmation status of a matched transaction changed.
+    TxStatusChanged { txid: Txid, status: TxStatus },
+    /// The balance of a watched address changed.
+    BalanceUpdated {
+        address: String,
+        balance: Balance,
+        /// Tokens held by the address, by category.
+        tokens: Vec<TokenBalance>,
+    },
+    /// A merkle block was received at this height.
+    ReceivedBlock(u64),
+    /// Rescan a range of blocks on the filtered peers.
+    RequestBlocks(MerkleScanRange),
+    /// How far the current rescan got.
+    ScanProgress(ScanProgress),
+    /// Watch the addresses of an HD account, from its extended public key and
+    /// the derivation path of that key, eg. `m/44'/145'/0'`.
+    AddXpub {
+        xpub: String,
+        path: String,
+        gap_limit: u32,
+        label: Option<String>,
+        /// Height to scan the account's history from, defaults to the tip.
+        birthday: Option<u64>,
+    },
+    /// Addresses newly derived for an HD account.
+    AccountExtended { xpub: String, addresses: Vec<String> },
+    /// Pay `amount` satoshis to `address` from the wallet, and broadcast the
+    /// transaction.
+    SendPayment {
+        address: String,
+        amount: u64,
+        /// Satoshis per byte.
+        fee_rate: u64,
+    },
+    /// Submit a raw transaction, in hex, to the negotiated peers.
+    BroadcastTx(String),
+    /// A peer's take on a transaction we broadcast.
+    TxBroadcast {
+        txid: Txid,
+        peer: PeerId,
+        status: PeerStatus,
+    },
+    /// A transaction could not be broadcast.
+    BroadcastFailed(String),
+    /// A transaction we broadcast came back through the bloom filter.
+    BroadcastSeen(Txid),
+}
+
+/// Bloom filter and the peers it was loaded onto.
+#[derive(Clone)]
+pub struct FilterState {
+    builder: FilterBuilder,
+    bloom: BloomFilter,
+    scripts: HashSet<Script>,
+    categories: HashSet<Category>,
+    /// Watched outpoints, by the item or account they pay to, if known.
+    outpoints: HashMap<OutPoint, Option<String>>,
+    peers: Vec<PeerId>,
+    filtered_peers: Vec<(PeerId, bool)>,
+}
+
+impl Default for FilterState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FilterState {
+    pub fn new() -> Self {
+        Self::with_fp_rate(filter::DEFAULT_FP_RATE)
+    }
+
+    /// Create an empty filter state targeting the given false-positive rate.
+    pub fn with_fp_rate(fp_rate: f64) -> Self {
+        let builder = FilterBuilder::new(fp_rate);
+        Self {
+            bloom: builder.build(),
+            builder,
+            scripts: HashSet::new(),
+            categories: HashSet::new(),
+            outpoints: HashMap::new(),
+            peers: Vec::with_capacity(32),
+            filtered_peers: Vec::with_capacity(32),
+        }
+    }
+
+    /// Peers we negotiated with.
+    pub fn peers(&self) -> &[PeerId] {
+        &self.peers
+    }
+
+    /// Peers the filter was sent to, and whether they are currently scanning.
+    pub fn filtered_peers(&self) -> &[(PeerId, bool)] {
+        &self.filtered_peers
+    }
+
+    /// Whether any item was added to the filter.
+    pub fn is_set(&self) -> bool {
+        !self.builder.is_empty()
+    }
+
+    /// Elements the filter is built from.
+    pub fn builder(&self) -> &FilterBuilder {
+        &self.builder
+    }
+
+    /// Change the false-positive rate and rebuild the filter.
+    pub fn set_fp_rate(&mut self, fp_rate: f64) {
+        self.builder.set_fp_rate(fp_rate);
+        self.bloom = self.builder.build();
+    }
+
+    /// Locking scripts of watched addresses.
+    pub fn watched_scripts(&self) -> impl Iterator<Item = &Script> {
+        self.scripts.iter()
+    }
+
+    /// Whether `script` pays to a watched address.
+    pub fn is_watched(&self, script: &Script) -> bool {
+        self.scripts.contains(script)
+    }
+
+    /// Watched token categories.
+    pub fn watched_categories(&self) -> impl Iterator<Item = &Category> {
+        self.categories.iter()
+    }
+
+    /// Whether `output` pays to a watched address, or holds tokens of a
+    /// watched category.
+    pub fn is_watched_output(&self, output: &TxOut) -> bool {
+        let (token, script) = token::split_output(output);
+        self.is_watched(&script) || token.is_some_and(|t| self.categories.contains(&t.category))
+    }
+
+    pub fn reset(&mut self) {
+        self.builder.clear();
+        self.scripts.clear();
+        self.categories.clear();
+        self.outpoints.clear();
+        self.bloom = self.builder.build();
+        self.filtered_peers.clear();
+        // You will get flooded with transactions, let's send an unset filter instead.
+        // _ = client.command(Command::BloomFilterClear);
+    }
+
+    /// Add a cashaddr, hex script, txid or `token:<category>` to the filter.
+    ///
+    /// Returns `true` if the filter changed.
+    pub fn add_bloom_item(&mut self, data: String) -> Result<bool, error::Error> {
+        if let Ok(address) = Address::decode(&data) {
+            return Ok(self.watch_address(&address));
+        }
+        // A bare 32 byte item is taken for a txid, categories need their prefix.
+        match token::parse_item(&data) {
+            Some(Ok(category)) => return Ok(self.watch_category(category)),
+            Some(Err(e)) => {
+                log::error!("Error decoding token category: {}", e);
+                return Ok(false);
+            }
+            None => {}
+        }
+        let Some((element, script)) = parse_hex_item(&data) else {
+            return Ok(false);
+        };
+        if script.is_none() && element.len() == TXID_LEN {
+            log::info!("Loading txid {:?}", hex::encode(&element));
+        }
+        let new_script = script.is_some_and(|s| self.scripts.insert(s));
+
+        Ok(self.insert(element) || new_script)
+    }
+
+    /// Remove an item previously added with [`FilterState::add_bloom_item`].
+    ///
+    /// Returns `true` if the filter changed.
+    pub fn remove_bloom_item(&mut self, data: &str) -> bool {
+        if let Some(Ok(category)) = token::parse_item(data) {
+            let removed = self.categories.remove(&category);
+            return self.remove_element(category.as_bytes()) || removed;
+        }
+        let (element, script) = match Address::decode(data) {
+            Ok(address) => (address.hash.clone(), Some(address.script_pubkey())),
+            Err(_) => match parse_hex_item(data) {
+                Some(item) => item,
+                None => return false,
+            },
+        };
+        let removed_script = script.is_some_and(|s| self.scripts.remove(&s));
+
+        self.remove_element(&element) || removed_script
+    }
+
+    /// Watch outputs paying to `address`, and record its locking script so
+    /// matched outputs can be recognized.
+    ///
+    /// Peers match the address hash against the data pushes of output scripts,
+    /// so the hash alone covers receives. Spends are covered by the outpoints of
+    /// matched outputs, see [`FilterState::add_outpoint`].
+    pub fn watch_address(&mut self, address: &Address) -> bool {
+        let new_script = self.scripts.insert(address.script_pubkey());
+        log::info!("Watching {:?} address {}", address.kind, address);
+
+        self.insert(address.hash.clone()) || new_script
+    }
+
+    /// Watch transactions creating or moving tokens of `category`.
+    ///
+    /// Peers match the category of token outputs against the filter, so the
+    /// category alone covers outputs. Spends are covered by outpoints, like
+    /// for addresses.
+    pub fn watch_category(&mut self, category: Category) -> bool {
+        let new_category = self.categories.insert(category);
+        log::info!("Watching token category {}", category);
+
+        self.insert(category.as_bytes().to_vec()) || new_category
+    }
+
+    /// Add an outpoint to the filter, so that transactions spending it match.
+    ///
+    /// `owner` is the data of the item or the extended public key of the
+    /// account the outpoint pays to, see [`FilterState::remove_outpoints`].
+    pub fn add_outpoint(&mut self, outpoint: &OutPoint, owner: Option<&str>) -> bool {
+        let owner = owner.map(str::to_owned);
+        self.outpoints
+            .entry(*outpoint)
+            .and_modify(|o| {
+                if o.is_none() {
+                    o.clone_from(&owner);
+                }
+            })
+            .or_insert(owner);
+
+        self.insert(serialize(outpoint))
+    }
+
+    /// Stop watching an outpoint, once its spend is confirmed.
+    pub fn remove_outpoint(&mut self, outpoint: &OutPoint) -> bool {
+        self.outpoints.remove(outpoint);
+        self.remove_element(&serialize(outpoint))
+    }
+
+    /// Stop watching the outpoints paying to `owner`, when it is removed.
+    ///
+    /// Returns `true` if the filter changed.
+    pub fn remove_outpoints(&mut self, owner: &str) -> bool {
+        let owned = self
+            .outpoints
+            .iter()
+            .filter(|(_, o)| o.as_deref() == Some(owner))
+            .map(|(outpoint, _)| *outpoint)
+            .collect::<Vec<_>>();
+
+        owned
+            .iter()
+            .fold(false, |changed, outpoint| self.remove_outpoint(outpoint) | changed)
+    }
+
+    /// Add a txid to the filter, so that the transaction matches when relayed.
+    pub fn watch_txid(&mut self, txid: &Txid) -> bool {
+        self.insert(serialize(txid))
+    }
+
+    /// Whether `outpoint` was added to the filter.
+    pub fn is_watched_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.builder.contains(&serialize(outpoint))
+    }
+
+    /// Whether `tx` actually involves a watched item, as opposed to being a
+    /// false positive of the filter or a transaction made up by the peer.
+    pub fn is_relevant(&self, tx: &Transaction) -> bool {
+        let txid = serialize(&tx.txid());
+        if self.builder.contains(&txid) {
+            return true;
+        }
+        if tx.output.iter().any(|o| self.is_watched_output(o))
+            || tx
+                .input
+                .iter()
+                .any(|i| self.is_watched_outpoint(&i.previous_output))
+        {
+            return true;
+        }
+        // Raw data pushes match anywhere in a script.
+        let scripts = tx
+            .output
+            .iter()
+            .map(|o| o.script_pubkey.as_bytes())
+            .chain(tx.input.iter().map(|i| i.script_sig.as_bytes()));
+
+        scripts
+            .flat_map(|script| self.builder.elements().map(move |e| (script, e)))
+            .any(|(script, e)| !e.is_empty() && script.windows(e.len()).any(|w| w == e))
+    }
+
+    fn remove_element(&mut self, element: &[u8]) -> bool {
+        if !self.builder.remove(element) {
+            return false;
+        }
+        self.bloom = self.builder.build();
+        log::debug!("Rebuilt filter with {} element(s)", self.builder.len());
+
+        true
+    }
+
+    fn insert(&mut self, element: Vec<u8>) -> bool {
+        if !self.builder.insert(element) {
+            return false;
+        }
+        self.bloom = self.builder.build();
+        log::debug!("Rebuilt filter with {} element(s)", self.builder.len());
+
+        true
+    }
+
+    /// Send the filter to `peers` and remember them as filtered.
+    pub fn send_bloom_filter<H: Handle>(
+        &mut self,
+        client: &H,
+        peers: Vec<(PeerId, bool)>,
+    ) -> Result<(), error::Error> {
+        let filter = self.bloom.clone();
+        for peer in &peers {
+            if !self.filtered_peers.iter().any(|p| p.0 == peer.0) {
+                self.filtered_peers.push(*peer);
+            }
+        }
+        let peers = peers.iter().map(|p| p.0).collect::<Vec<_>>();
+        client
+            .command(Command::LoadBloomFilter((filter, peers)))
+            .map_err(error::Error::from)?;
+        Ok(())
+    }
+
+    /// Send the current filter again to the peers it was already loaded onto.
+    pub fn reload_bloom_filter<H: Handle>(&mut self, client: &H) -> Result<(), error::Error> {
+        if self.filtered_peers.is_empty() {
+            return Ok(());
+        }
+        self.send_bloom_filter(client, self.filtered_peers.clone())
+    }
+}
+
+/// Filter element and locking script, if any, of a hex script or txid.
+fn parse_hex_item(data: &str) -> Option<(Vec<u8>, Option<Script>)> {
+    let mut bytes = match hex::decode(data) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("Error decoding hex: {}", e);
+            return None;
+        }
+    };
+    if TXID_LEN == bytes.len() {
+        // Txids are displayed in reverse byte order.
+        bytes.reverse();
+        return Some((bytes, None));
+    }
+    if let Some((_, hash)) = address::parse_script(&bytes) {
+        return Some((hash.to_vec(), Some(Script::from(bytes))));
+    }
+    Some((bytes, None))
+}
+
+/// Drives a bloom filtered client from front-end [`UIMessage`]s, and reports
+/// client events back to the front-end.
+pub struct Watcher<H> {
+    client: H,
+    network: Network,
+    filter_state: FilterState,
+    txids: HashSet<Txid>,
+    proofs: HashMap<Txid, Confirmation>,
+    history: History,
+    utxos: UtxoSet,
+    watch_list: Option<WatchList>,
+    accounts: Vec<HdAccount>,
+    wallet: Option<Wallet>,
+    broadcasts: HashMap<Txid, Broadcast>,
+    scan: Option<Scan>,
+    scan_chunk_size: u64,
+    /// Transactions proven by merkle blocks during the rescan and not received
+    /// yet, with the peer that sent the block and when.
+    awaited: HashMap<Txid, (PeerId, Instant)>,
+    /// Rescan bringing the watched items' history up to the tip, see
+    /// [`WatchList::scan_start`].
+    catch_up: Option<MerkleScanRange>,
+    filter_refresh: Duration,
+    filter_loaded_at: Instant,
+    shutdown: Option<Shutdown>,
+    tip: u64,
+    /// Whether the client is ready, and `tip` known.
+    ready: bool,
+    auto_load_filter: bool,
+}
+
+impl<H: Handle> Watcher<H> {
+    pub fn new(client: H, network: client::Network) -> Self {
+        Self {
+            client,
+            network,
+            filter_state: FilterState::new(),
+            txids: HashSet::new(),
+            proofs: HashMap::new(),
+            history: History::new(),
+            utxos: UtxoSet::new(),
+            watch_list: None,
+            accounts: Vec::new(),
+            wallet: None,
+            broadcasts: HashMap::new(),
+            scan: None,
+            scan_chunk_size: scan::DEFAULT_CHUNK_SIZE,
+            awaited: HashMap::new(),
+            catch_up: None,
+            filter_refresh: DEFAULT_FILTER_REFRESH,
+            filter_loaded_at: Instant::now(),
+            shutdown: None,
+            tip: 0,
+            ready: false,
+            auto_load_filter: false,
+        }
+    }
+
+    /// Target false-positive rate of the bloom filter.
+    pub fn fp_rate(mut self, fp_rate: f64) -> Self {
+        self.filter_state.set_fp_rate(fp_rate);
+        self
+    }
+
+    /// How often the filter is loaded again onto the filtered peers.
+    pub fn filter_refresh(mut self, interval: Duration) -> Self {
+        self.filter_refresh = interval;
+        self
+    }
+
+    /// Blocks a peer scans in one go, when a rescan is split across peers.
+    pub fn scan_chunk_size(mut self, blocks: u64) -> Self {
+        self.scan_chunk_size = blocks.max(1);
+        self
+    }
+
+    /// Stop [`Watcher::run`] when `shutdown` is triggered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Persist watched items to `watch_list`, and load the items it already holds
+    /// into the filter. A rescan interrupted by a restart is resumed.
+    pub fn with_watch_list(mut self, watch_list: WatchList) -> Result<Self, error::Error> {
+        for item in watch_list.items() {
+            self.filter_state.add_bloom_item(item.data.clone())?;
+        }
+        for (outpoint, owner) in watch_list.outpoints() {
+            self.filter_state.add_outpoint(outpoint, owner);
+        }
+        for record in watch_list.accounts() {
+            // An account stored by another version or for another network
+            // shouldn't keep the others from being watched.
+            let mut account = match HdAccount::from_record(record, self.network) {
+                Ok(account) => account,
+                Err(e) => {
+                    log::error!("Skipping account {}: {}", record.xpub, e);
+                    continue;
+                }
+            };
+            for address in account.extend()? {
+                self.filter_state.watch_address(&address);
+            }
+            self.accounts.push(account);
+        }
+        if !watch_list.scan().is_empty() {
+            let now = Instant::now();
+            let scan = Scan::resume(watch_list.scan().to_vec(), self.scan_chunk_size, now);
+            if !scan.is_done() {
+                log::info!("Resuming rescan at {}", scan.progress(now));
+                self.scan = Some(scan);
+            }
+        }
+        self.watch_list = Some(watch_list);
+        Ok(self)
+    }
+
+    /// Watch the addresses of `wallet`, and spend from them on
+    /// [`UIMessage::SendPayment`].
+    pub fn with_wallet(mut self, wallet: Wallet) -> Self {
+        for address in wallet.addresses() {
+            self.filter_state.watch_address(&address);
+        }
+        self.wallet = Some(wallet);
+        self
+    }
+
+    /// Current filter state.
+    pub fn filter_state(&self) -> &FilterState {
+        &self.filter_state
+    }
+
+    /// Rescan in progress, if any.
+    pub fn scan(&self) -> Option<&Scan> {
+        self.scan.as_ref()
+    }
+
+    /// Txids of all matched transactions seen so far.
+    pub fn txids(&self) -> &HashSet<Txid> {
+        &self.txids
+    }
+
+    /// Confirmation status of matched transactions.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Unspent outputs of watched addresses.
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    /// Watched HD accounts.
+    pub fn accounts(&self) -> &[HdAccount] {
+        &self.accounts
+    }
+
+    /// Transactions we broadcast.
+    pub fn broadcasts(&self) -> &HashMap<Txid, Broadcast> {
+        &self.broadcasts
+    }
+
+    /// Persisted watch list, if any.
+    pub fn watch_list(&self) -> Option<&WatchList> {
+        self.watch_list.as_ref()
+    }
+
+    /// Load the current filter onto every newly negotiated peer, instead of
+    /// waiting for a [`UIMessage::SendLoadFilter`].
+    pub fn auto_load_filter(mut self, enabled: bool) -> Self {
+        self.auto_load_filter = enabled;
+        self
+    }
+
+    /// Watch the receive and change addresses of an HD account, deriving
+    /// `gap_limit` addresses past the last used one on each chain.
+    ///
+    /// The account's history is scanned from `birthday`, or it is assumed to
+    /// be unused before the tip.
+    ///
+    /// Returns the newly derived addresses, none if the account was already
+    /// watched.
+    pub fn watch_xpub(
+        &mut self,
+        xpub: &str,
+        path: &str,
+        gap_limit: u32,
+        label: Option<String>,
+        birthday: Option<u64>,
+    ) -> Result<Vec<Address>, error::Error> {
+        let mut account = HdAccount::new(xpub, path, gap_limit, self.network)?;
+        if self.accounts.iter().any(|a| a.xpub == account.xpub) {
+            return Ok(Vec::new());
+        }
+        account.label = label;
+        account.birthday = birthday.or(self.ready.then_some(self.tip));
+        log::info!("Watching account {} at {}", account.xpub, account.path);
+
+        let addresses = account.extend()?;
+        let mut changed = false;
+        for address in &addresses {
+            changed |= self.filter_state.watch_address(address);
+        }
+        if changed {
+            self.reload_filter(Instant::now())?;
+        }
+        if let Some(list) = self.watch_list.as_mut() {
+            if list.upsert_account(account.record()) {
+                list.save()?;
+            }
+        }
+        self.accounts.push(account);
+        if birthday.is_some() {
+            self.catch_up()?;
+        }
+        Ok(addresses)
+    }
+
+    /// Pay `amount` satoshis to `address` from the wallet's unspent outputs,
+    /// and broadcast the signed transaction. The outputs it spends aren't
+    /// spent again, unless no peer takes it.
+    ///
+    /// Returns the txid and the peers it was submitted to.
+    pub fn send_payment(
+        &mut self,
+        address: &str,
+        amount: u64,
+        fee_rate: u64,
+    ) -> Result<(Txid, Vec<PeerId>), error::Error> {
+        let recipient = Address::decode(address)?;
+        let wallet = self.wallet.as_mut().ok_or(error::Error::WalletNameNotFound)?;
+        let tx = wallet.build_transaction(self.utxos.spendable(), &[(recipient, amount)], fee_rate)?;
+
+        // Watch the change address, if one was handed out.
+        let mut changed = false;
+        for address in wallet.addresses() {
+            changed |= self.filter_state.watch_address(&address);
+        }
+        if changed {
+            self.reload_filter(Instant::now())?;
+        }
+        log::info!("Paying {} to {} in {}", amount, address, tx.txid());
+
+        // The inputs stay reserved while the transaction waits for a peer.
+        self.utxos.reserve(&tx);
+        self.broadcast(&hex::encode(serialize(&tx))).inspect_err(|_| {
+            self.utxos.release(tx.txid());
+        })
+    }
+
+    /// Broadcast a raw transaction, in hex.
+    ///
+    /// The transaction is submitted to the negotiated peers, or kept until a
+    /// peer is negotiated if there are none. Its txid is added to the filter so
+    /// that we notice when it is relayed back to us.
+    ///
+    /// Returns the txid and the peers it was submitted to.
+    pub fn broadcast(&mut self, raw: &str) -> Result<(Txid, Vec<PeerId>), error::Error> {
+        let transaction = broadcast::decode(raw)?;
+        let txid = transaction.txid();
+
+        if self.filter_state.watch_txid(&txid) {
+            self.reload_filter(Instant::now())?;
+        }
+        self.broadcasts
+            .entry(txid)
+            .or_insert_with(|| Broadcast::new(transaction));
+
+        if self.filter_state.peers.is_empty() {
+            log::info!("Queued transaction {} until a peer is negotiated", txid);
+            return Ok((txid, Vec::new()));
+        }
+        Ok((txid, self.submit(txid)?))
+    }
+
+    /// Process client events and front-end messages until shutdown is
+    /// triggered, or until the client or the front-end goes away. Persisted
+    /// state is flushed before returning.
+    ///
+    /// Blocks while there is nothing to do, waking up every [`TICK_INTERVAL`]
+    /// for periodic tasks.
+    pub fn run(
+        &mut self,
+        events: &Receiver<client::Event>,
+        ui_input_rx: &Receiver<UIMessage>,
+        ui_show_tx: &Sender<UIMessage>,
+    ) -> Result<(), error::Error> {
+        let result = self.run_loop(events, ui_input_rx, ui_show_tx);
+        let flushed = self.flush();
+
+        result.and(flushed)
+    }
+
+    fn run_loop(
+        &mut self,
+        events: &Receiver<client::Event>,
+        ui_input_rx: &Receiver<UIMessage>,
+        ui_show_tx: &Sender<UIMessage>,
+    ) -> Result<(), error::Error> {
+        let ticker = chan::tick(TICK_INTERVAL);
+        let never = chan::never();
+        let shutdown = self.shutdown.as_ref().map_or(&never, |s| s.receiver()).clone();
+
+        loop {
+            let flow = chan::select! {
+                recv(shutdown) -> _ => ControlFlow::Break(()),
+                recv(ui_input_rx) -> msg => match msg {
+                    Ok(msg) => self.handle_user_input(msg, ui_show_tx)?,
+                    Err(_) => {
+                        log::info!("Front-end disconnected, stopping the watcher");
+                        ControlFlow::Break(())
+                    }
+                },
+                recv(events) -> event => match event {
+                    Ok(event) => self.handle_client_event(event, ui_show_tx)?,
+                    Err(_) => {
+                        log::info!("Client disconnected, stopping the watcher");
+                        ControlFlow::Break(())
+                    }
+                },
+                recv(ticker) -> now => {
+                    self.handle_tick(now.unwrap_or_else(|_| Instant::now()), ui_show_tx)?;
+                    ControlFlow::Continue(())
+                }
+            };
+            if flow.is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the watch list and the wallet to disk.
+    pub fn flush(&self) -> Result<(), error::Error> {
+        if let Some(list) = self.watch_list.as_ref() {
+            list.save()?;
+        }
+        if let Some(wallet) = self.wallet.as_ref() {
+            wallet.save()?;
+        }
+        log::debug!("Flushed watcher state");
+
+        Ok(())
+    }
+
+    /// Run periodic tasks that are due at `now`: hand the rescan's pending
+    /// and stalled chunks to idle filtered peers, refresh the filter on the
+    /// filtered peers, and give up on peers that didn't ask for a broadcast
+    /// transaction.
+    pub fn handle_tick(
+        &mut self,
+        now: Instant,
+        ui_show_tx: &Sender<UIMessage>,
+    ) -> Result<(), error::Error> {
+        if let Some(scan) = self.scan.as_mut() {
+            for (peer, range) in scan.stalled(now) {
+                log::info!(
+                    "Peer {} stalled scanning blocks {} to {}, retrying",
+                    peer,
+                    range.begin(),
+                    range.end()
+                );
+            }
+        }
+        self.dispatch_scan()?;
+        // Proven transactions that never came don't hold the rescan forever.
+        self.awaited
+            .retain(|_, (_, at)| now.saturating_duration_since(*at) < scan::STALL_TIMEOUT);
+        self.finish_scan(ui_show_tx)?;
+        for (txid, b) in self.broadcasts.iter_mut() {
+            let rejected = b.reject_unacknowledged(now);
+            for peer in &rejected {
+                log::info!("Peer {} rejected transaction {}", peer, txid);
+                notify(
+                    ui_show_tx,
+                    UIMessage::TxBroadcast {
+                        txid: *txid,
+                        peer: *peer,
+                        status: PeerStatus::Rejected,
+                    },
+                );
+            }
+            // Its outputs are reserved again if it is submitted again.
+            if !rejected.is_empty() && b.is_pending() && self.utxos.release(*txid) {
+                log::info!("Outputs spent by {} are spendable again", txid);
+            }
+        }
+        if now.saturating_duration_since(self.filter_loaded_at) >= self.filter_refresh {
+            let peers = self.filter_state.filtered_peers.len();
+            if peers > 0 {
+                log::debug!("Refreshing the filter on {} peer(s)", peers);
+            }
+            self.reload_filter(now)?;
+        }
+        Ok(())
+    }
+
+    /// Load the filter onto every negotiated peer.
+    fn load_filter(&mut self) -> Result<(), error::Error> {
+        let peers = self
+            .filter_state
+            .peers
+            .iter()
+            .map(|p| (*p, false))
+            .collect::<Vec<_>>();
+        self.filter_state.send_bloom_filter(&self.client, peers)?;
+        self.filter_loaded_at = Instant::now();
+
+        Ok(())
+    }
+
+    /// Load the filter again onto the filtered peers, once it changed or to
+    /// refresh it. The next refresh is due [`Watcher::filter_refresh`] later.
+    fn reload_filter(&mut self, now: Instant) -> Result<(), error::Error> {
+        self.filter_state.reload_bloom_filter(&self.client)?;
+        self.filter_loaded_at = now;
+
+        Ok(())
+    }
+
+    /// Rescan `range` on the filtered peers, split into chunks. Heights
+    /// already part of the current rescan aren't scanned twice.
+    fn rescan(&mut self, range: MerkleScanRange) -> Result<(), error::Error> {
+        let chunk_size = self.scan_chunk_size;
+        let scan = self
+            .scan
+            .get_or_insert_with(|| Scan::new(chunk_size, Instant::now()));
+        if scan.push(&range) {
+            log::info!("Rescanning blocks {} to {}", range.begin(), range.end());
+            self.save_scan()?;
+        }
+        if self.filter_state.filtered_peers.is_empty() {
+            log::info!("No filtered peer to rescan {:?} with, retrying later", range);
+        }
+        self.dispatch_scan()
+    }
+
+    /// Hand the rescan's pending chunks to the filtered peers that aren't
+    /// scanning one.
+    fn dispatch_scan(&mut self) -> Result<(), error::Error> {
+        let Some(scan) = self.scan.as_mut() else {
+            return Ok(());
+        };
+        for (peer, _) in &self.filter_state.filtered_peers {
+            if scan.is_scanning(peer) {
+                continue;
+            }
+            let Some(range) = scan.assign(*peer, Instant::now()) else {
+                break;
+            };
+            log::debug!("Scanning blocks {} to {} on {}", range.begin(), range.end(), peer);
+            self.client.command(Command::MerkleBlockRescan {
+                from: std::ops::Bound::Included(range.begin()),
+                to: std::ops::Bound::Included(range.end()),
+                peers: vec![*peer],
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Record a merkle block received from `peer` in the rescan, and report
+    /// its progress.
+    fn scan_received(
+        &mut self,
+        peer: PeerId,
+        height: u64,
+        ui_show_tx: &Sender<UIMessage>,
+    ) -> Result<(), error::Error> {
+        let Some(scan) = self.scan.as_mut() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let completed = scan.received(&peer, height, now);
+        // Completion is reported once the transactions of the last blocks
+        // were received, see `finish_scan`.
+        if let Some(progress) = scan.report(now).filter(|p| !p.is_done()) {
+            log::info!("Rescan at {}", progress);
+            notify(ui_show_tx, UIMessage::ScanProgress(progress));
+        }
+        let Some(range) = completed else {
+            return Ok(());
+        };
+        log::debug!("Scanned blocks {} to {} on {}", range.begin(), range.end(), peer);
+
+        self.caught_up();
+        self.save_scan()?;
+        self.dispatch_scan()?;
+        self.finish_scan(ui_show_tx)
+    }
+
+    /// Report the rescan as complete and forget it, once all its chunks were
+    /// scanned and the transactions its merkle blocks proved were applied.
+    fn finish_scan(&mut self, ui_show_tx: &Sender<UIMessage>) -> Result<(), error::Error> {
+        if !self.awaited.is_empty() {
+            return Ok(());
+        }
+        let Some(scan) = self.scan.take_if(|s| s.is_done()) else {
+            return Ok(());
+        };
+        let progress = scan.progress(Instant::now());
+        log::info!("Rescan complete, {}", progress);
+        notify(ui_show_tx, UIMessage::ScanProgress(progress));
+
+        self.save_scan()
+    }
+
+    /// Tell the front-end where the rescan stands, eg. when a requested range
+    /// was already part of it.
+    fn report_scan(&mut self, ui_show_tx: &Sender<UIMessage>) -> Result<(), error::Error> {
+        match self.scan.as_ref().map(|s| s.progress(Instant::now())) {
+            Some(progress) if !progress.is_done() => {
+                notify(ui_show_tx, UIMessage::ScanProgress(progress));
+                Ok(())
+            }
+            _ => self.finish_scan(ui_show_tx),
+        }
+    }
+
+    /// Hand the chunk `peer` was scanning to another peer, now or on the next
+    /// tick.
+    fn retry_scan(&mut self, peer: PeerId) -> Option<MerkleScanRange> {
+        let range = self.scan.as_mut()?.release(&peer)?;
+        log::info!("Retrying blocks {} to {} scanned by {}", range.begin(), range.end(), peer);
+
+        Some(range)
+    }
+
+    /// Store the rescan's chunks in the watch list, to resume it after a restart.
+    fn save_scan(&mut self) -> Result<(), error::Error> {
+        let Some(list) = self.watch_list.as_mut() else {
+            return Ok(());
+        };
+        list.set_scan(self.scan.as_ref().map_or_else(Vec::new, |s| s.chunks().to_vec()));
+        list.save()
+    }
+
+    /// Scan the watched items' history from the first height left to scan up
+    /// to the tip, unless a scan already covers it.
+    fn catch_up(&mut self) -> Result<(), error::Error> {
+        let Some(from) = self.watch_list.as_ref().and_then(|l| l.scan_start()) else {
+            return Ok(());
+        };
+        if !self.ready || from > self.tip {
+            return Ok(());
+        }
+        if let Some(c) = &self.catch_up {
+            if c.begin() <= from && c.end() >= self.tip {
+                return Ok(());
+            }
+        }
+        let range = MerkleScanRange::new(from, self.tip);
+        log::info!("Scanning blocks {} to {} for watched items", from, self.tip);
+
+        self.catch_up = Some(range.clone());
+        self.rescan(range)
+    }
+
+    /// Record the scanned height once the rescan covers the catch-up range.
+    /// The watch list is saved with the rescan.
+    fn caught_up(&mut self) {
+        let scan = self.scan.as_ref();
+        let Some(range) = self.catch_up.take_if(|r| scan.is_some_and(|s| s.covers(r))) else {
+            return;
+        };
+        log::info!("Scanned blocks up to {}", range.end());
+        if let Some(list) = self.watch_list.as_mut() {
+            list.set_scanned_height(range.end());
+        }
+    }
+
+    /// Handle a single client event, as [`Watcher::run`] does.
+    pub fn handle_client_event(
+        &mut self,
+        event: client::Event,
+        ui_show_tx: &chan::Sender<UIMessage>,
+    ) -> Result<ControlFlow<()>, error::Error> {
+        match event {
+            Event::Ready { tip, time, .. } => {
+                self.tip = tip;
+                self.ready = true;
+                self.history.set_tip(tip);
+                notify(ui_show_tx, UIMessage::HeaderLoaded(tip));
+                notify(ui_show_tx, UIMessage::NetworkConnected(self.network));
+                if let Some(list) = self.watch_list.as_mut() {
+                    // Items added before the tip was known are born at the tip.
+                    if list.set_birthdays(tip) {
+                        list.save()?;
+                    }
+                    for account in self.accounts.iter_mut() {
+                        account.birthday.get_or_insert(tip);
+                    }
+                    if !list.is_empty() {
+                        notify(
+                            ui_show_tx,
+                            UIMessage::WatchListLoaded(list.items().to_vec()),
+                        );
+                    }
+                }
+                log::info!("Client Ready {:?}", time.to_string());
+                self.catch_up()?;
+            }
+            Event::BlockConnected { height, .. } => {
+                self.tip = height;
+                notify(ui_show_tx, UIMessage::BlockConnected(height));
+                self.report_statuses(self.history.block_connected(height), ui_show_tx);
+            }
+            Event::BlockDisconnected { height, hash, .. } => {
+                log::info!("Block {} disconnected at height {}", hash, height);
+                self.tip = height.saturating_sub(1);
+
+                let reverted = self.history.block_disconnected(height, hash);
+                let mut changed = HashSet::new();
+                for (txid, _) in &reverted {
+                    self.proofs.remove(txid);
+                    changed.extend(self.utxos.unconfirm(*txid));
+                }
+                self.report_balances(changed, ui_show_tx);
+                self.report_statuses(reverted, ui_show_tx);
+            }
+            Event::PeerNegotiated { addr, .. } => {
+                self.filter_state.peers.push(addr);
+                if self.auto_load_filter && self.filter_state.is_set() {
+                    self.filter_state
+                        .send_bloom_filter(&self.client, vec![(addr, false)])?;
+                }
+                self.submit_pending(ui_show_tx);
+            }
+            Event::TxStatusChanged {
+                txid,
+                status: client::TxStatus::Acknowledged { peer },
+            } => {
+                if let Some(b) = self.broadcasts.get_mut(&txid) {
+                    if b.set_status(peer, PeerStatus::Acknowledged) {
+                        log::info!("Peer {} acknowledged transaction {}", peer, txid);
+                        // A peer asking late takes back outputs released on rejection.
+                        self.utxos.reserve(&b.transaction);
+                        notify(
+                            ui_show_tx,
+                            UIMessage::TxBroadcast {
+                                txid,
+                                peer,
+                                status: PeerStatus::Acknowledged,
+                            },
+                        );
+                    }
+                }
+            }
+            Event::MerkleBlockScanStarted { peer, .. } => {
+                self.filter_state.filtered_peers.iter_mut().for_each(|p| {
+                    if p.0 == peer {
+                        p.1 = true;
+                    }
+                });
+                notify(ui_show_tx, UIMessage::BlocksDownloading(true));
+            }
+            Event::MerkleBlockRescanStopped { peer, .. } => {
+                self.filter_state.filtered_peers.iter_mut().for_each(|p| {
+                    if p.0 == peer {
+                        p.1 = false;
+                    }
+                });
+                if self.filter_state.filtered_peers.iter().all(|p| !p.1) {
+                    notify(ui_show_tx, UIMessage::BlocksDownloading(false));
+                }
+                // The peer gave up before the end of its chunk, hand it out
+                // again on the next tick, even if it sent nothing.
+                if let Some(range) = self.scan.as_mut().and_then(|s| s.interrupted(&peer)) {
+                    log::info!(
+                        "Peer {} stopped scanning blocks {} to {}, retrying later",
+                        peer,
+                        range.begin(),
+                        range.end()
+                    );
+                }
+            }
+            Event::PeerDisconnected { addr, .. } => {
+                self.filter_state.peers.retain(|peer| peer != &addr);
+                self.filter_state
+                    .filtered_peers
+                    .retain(|peer| peer.0 != addr);
+                if self.retry_scan(addr).is_some() {
+                    self.dispatch_scan()?;
+                }
+                // Transactions of its blocks won't come anymore.
+                self.awaited.retain(|_, (peer, _)| *peer != addr);
+                self.finish_scan(ui_show_tx)?;
+
+                for (txid, b) in self.broadcasts.iter_mut() {
+                    if b.set_status(addr, PeerStatus::Dropped) {
+                        log::info!("Peer {} dropped transaction {}", addr, txid);
+                        notify(
+                            ui_show_tx,
+                            UIMessage::TxBroadcast {
+                                txid: *txid,
+                                peer: addr,
+                                status: PeerStatus::Dropped,
+                            },
+                        );
+                    }
+                }
+            }
+            Event::ReceivedMatchedTx { transaction } => {
+                let txid = transaction.txid();
+                let confirmation = self.proofs.get(&txid).copied();
+                self.awaited.remove(&txid);
+
+                // Outpoints of confirmed spends are pruned, the spends stay relevant.
+                if !self.txids.contains(&txid) && !self.filter_state.is_relevant(&transaction) {
+                    log::debug!("Ignoring unrelated transaction {}", txid);
+                    self.proofs.remove(&txid);
+                    self.finish_scan(ui_show_tx)?;
+                    return Ok(ControlFlow::Continue(()));
+                }
+                match confirmation {
+                    Some(c) => log::info!("Matched {} at {}:{}", txid, c.height, c.index),
+                    None => log::info!("Matched unconfirmed {}", txid),
+                }
+                if let Some(b) = self.broadcasts.get_mut(&txid).filter(|b| !b.seen) {
+                    log::info!("Broadcast transaction {} was relayed back", txid);
+                    b.seen = true;
+                    notify(ui_show_tx, UIMessage::BroadcastSeen(txid));
+                }
+                let first_seen = self.txids.insert(txid);
+                self.extend_accounts(&transaction, ui_show_tx)?;
+                self.track_outputs(&transaction, confirmation.is_some())?;
+                if let Some(status) = self.history.update(txid, confirmation) {
+                    self.report_statuses(vec![(txid, status)], ui_show_tx);
+                }
+
+                // Proofs coming later are applied by the merkle block, and
+                // spending again inputs we already saw spent would leave them
+                // waiting for an output that was already resolved.
+                if first_seen {
+                    let filter_state = &self.filter_state;
+                    let height = confirmation.map(|c| c.height);
+                    let changed = self
+                        .utxos
+                        .apply(&transaction, height, |script| filter_state.is_watched(script));
+                    self.report_balances(changed, ui_show_tx);
+                }
+
+                let tokens = transaction
+                    .output
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(vout, o)| Some((vout as u32, token::split_output(o).0?)))
+                    .collect::<Vec<_>>();
+                for (vout, token) in &tokens {
+                    log::info!("Output {}:{} holds token {}", txid, vout, token);
+                }
+                notify(
+                    ui_show_tx,
+                    UIMessage::ReceivedMatchedTx {
+                        transaction,
+                        confirmation,
+                        tokens,
+                    },
+                );
+                self.finish_scan(ui_show_tx)?;
+            }
+            Event::ReceivedMerkleBlock {
+                peer,
+                merkle_block,
+                height,
+                ..
+            } => {
+                match merkle::verify(&self.client, &merkle_block, height) {
+                    Ok(proven) => {
+                        let mut changed = HashSet::new();
+                        let mut statuses = Vec::new();
+                        for (txid, confirmation) in proven {
+                            changed.extend(self.utxos.confirm(txid, height));
+                            statuses.extend(
+                                self.history
+                                    .confirm(txid, confirmation)
+                                    .map(|status| (txid, status)),
+                            );
+                            self.proofs.insert(txid, confirmation);
+                            // The rescan isn't complete until the transaction
+                            // itself comes in.
+                            if self.scan.is_some() && !self.txids.contains(&txid) {
+                                self.awaited.insert(txid, (peer, Instant::now()));
+                            }
+                        }
+                        self.report_balances(changed, ui_show_tx);
+                        self.report_statuses(statuses, ui_show_tx);
+                    }
+                    Err(e) => {
+                        log::warn!("Rejecting merkle block: {}", e);
+                        return Ok(ControlFlow::Continue(()));
+                    }
+                }
+                notify(ui_show_tx, UIMessage::ReceivedBlock(height));
+                self.scan_received(peer, height, ui_show_tx)?;
+            }
+            Event::PeerLoadedBloomFilter { peer, .. } => {
+                if self.filter_state.is_set() {
+                    notify(ui_show_tx, UIMessage::PeerLoadedFilter(peer));
+                }
+            }
+            _ => {}
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Handle a single front-end message, as [`Watcher::run`] does.
+    pub fn handle_user_input(
+        &mut self,
+        ui_input: UIMessage,
+        ui_show_tx: &Sender<UIMessage>,
+    ) -> Result<ControlFlow<()>, error::Error> {
+        match ui_input {
+            UIMessage::AddBloomItem(data) => match hd::parse_input(&data) {
+                Some((xpub, path)) => {
+                    self.add_xpub(xpub, path, hd::DEFAULT_GAP_LIMIT, None, None, ui_show_tx)
+                }
+                None => self.add_watch_item(data, None, None)?,
+            },
+            UIMessage::AddWatchItem {
+                data,
+                label,
+                birthday,
+            } => match hd::parse_input(&data) {
+                Some((xpub, path)) => self.add_xpub(
+                    xpub,
+                    path,
+                    hd::DEFAULT_GAP_LIMIT,
+                    label,
+                    birthday,
+                    ui_show_tx,
+                ),
+                None => self.add_watch_item(data, label, birthday)?,
+            },
+            UIMessage::RemoveBloomItem(data) => {
+                // Accounts are given as `<xpub> [path]`, as when adding them.
+                let xpub = hd::parse_input(&data).map_or(data.as_str(), |(xpub, _)| xpub);
+                let mut changed = self.filter_state.remove_bloom_item(&data);
+                changed |= self.filter_state.remove_outpoints(&data);
+                changed |= self.filter_state.remove_outpoints(xpub);
+                if let Some(i) = self.accounts.iter().position(|a| a.xpub.to_string() == xpub) {
+                    for address in self.accounts.remove(i).addresses() {
+                        changed |= self.filter_state.remove_bloom_item(&address.encode());
+                    }
+                }
+                if changed {
+                    self.reload_filter(Instant::now())?;
+                }
+                if let Some(list) = self.watch_list.as_mut() {
+                    if list.remove(&data) | list.remove_account(xpub) {
+                        list.save()?;
+                    }
+                }
+            }
+            UIMessage::AddXpub {
+                xpub,
+                path,
+                gap_limit,
+                label,
+                birthday,
+            } => self.add_xpub(&xpub, &path, gap_limit, label, birthday, ui_show_tx),
+            UIMessage::ListMatchedTxs(reply) => {
+                let txs = self
+                    .txids
+                    .iter()
+                    .map(|txid| (*txid, self.history.status(txid)))
+                    .collect();
+                // The requester may have given up waiting.
+                _ = reply.send(txs);
+            }
+            UIMessage::SendLoadFilter => {
+                if !self.filter_state.peers.is_empty() {
+                    self.load_filter()?;
+                }
+            }
+            UIMessage::ResetFilter => {}
+            UIMessage::ClearFilterAndPeers => {
+                self.filter_state.reset();
+                self.accounts.clear();
+                self.txids.clear();
+                self.utxos.clear();
+                self.proofs.clear();
+                self.history.clear();
+                self.scan = None;
+                self.awaited.clear();
+                self.catch_up = None;
+                if let Some(list) = self.watch_list.as_mut() {
+                    list.clear();
+                    list.save()?;
+                }
+                self.load_filter()?;
+            }
+            UIMessage::RequestBlocks(range) => {
+                self.rescan(range)?;
+                self.report_scan(ui_show_tx)?;
+            }
+            UIMessage::SendPayment {
+                address,
+                amount,
+                fee_rate,
+            } => match self.send_payment(&address, amount, fee_rate) {
+                Ok((txid, peers)) => self.report_submitted(txid, peers, ui_show_tx),
+                Err(e) => {
+                    log::warn!("Failed to pay {}: {}", address, e);
+                    notify(ui_show_tx, UIMessage::BroadcastFailed(e.to_string()));
+                }
+            },
+            UIMessage::BroadcastTx(raw) => match self.broadcast(&raw) {
+                Ok((txid, peers)) => self.report_submitted(txid, peers, ui_show_tx),
+                Err(e) => {
+                    log::warn!("Failed to broadcast transaction: {}", e);
+                    notify(ui_show_tx, UIMessage::BroadcastFailed(e.to_string()));
+                }
+            },
+            _ => {}
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Submit a broadcast transaction to the negotiated peers.
+    fn submit(&mut self, txid: Txid) -> Result<Vec<PeerId>, error::Error> {
+        let Some(b) = self.broadcasts.get_mut(&txid) else {
+            return Ok(Vec::new());
+        };
+        let peers = self
+            .client
+            .submit_transaction(b.transaction.clone())?
+            .into_iter()
+            .collect::<Vec<_>>();
+        b.submitted(&peers, Instant::now());
+        self.utxos.reserve(&b.transaction);
+
+        Ok(peers)
+    }
+
+    /// Submit transactions that were waiting for a peer.
+    fn submit_pending(&mut self, ui_show_tx: &Sender<UIMessage>) {
+        let pending = self
+            .broadcasts
+            .iter()
+            .filter(|(_, b)| b.is_pending())
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+
+        for txid in pending {
+            match self.submit(txid) {
+                Ok(peers) => self.report_submitted(txid, peers, ui_show_tx),
+                Err(e) => log::warn!("Failed to submit transaction {}: {}", txid, e),
+            }
+        }
+    }
+
+    fn report_submitted(&self, txid: Txid, peers: Vec<PeerId>, ui_show_tx: &Sender<UIMessage>) {
+        for peer in peers {
+            log::info!("Submitted transaction {} to {}", txid, peer);
+            notify(
+                ui_show_tx,
+                UIMessage::TxBroadcast {
+                    txid,
+                    peer,
+                    status: PeerStatus::Submitted,
+                },
+            );
+        }
+    }
+
+    fn report_statuses(&self, statuses: Vec<(Txid, TxStatus)>, ui_show_tx: &Sender<UIMessage>) {
+        for (txid, status) in statuses {
+            log::info!("Transaction {} is {}", txid, status);
+            notify(ui_show_tx, UIMessage::TxStatusChanged { txid, status });
+        }
+    }
+
+    fn report_balances(&self, scripts: HashSet<Script>, ui_show_tx: &Sender<UIMessage>) {
+        let prefix = address::prefix(self.network);
+
+        for script in scripts {
+            let balance = self.utxos.balance(&script);
+            let tokens = self.utxos.tokens(&script);
+            let address = Address::from_script(prefix, &script)
+                .map(|a| a.encode())
+                .unwrap_or_else(|| hex::encode(script.as_bytes()));
+            log::info!(
+                "Balance of {}: {} confirmed, {} unconfirmed",
+                address,
+                balance.confirmed,
+                balance.unconfirmed
+            );
+            for token in &tokens {
+                log::info!("Tokens of {}: {}", address, token);
+            }
+            notify(
+                ui_show_tx,
+                UIMessage::BalanceUpdated {
+                    address,
+                    balance,
+                    tokens,
+                },
+            );
+        }
+    }
+
+    /// Add the outpoints of outputs paying to watched scripts to the filter,
+    /// so that their spends match without relying on the peer updating it.
+    /// Outpoints spent by a confirmed `transaction` are removed.
+    fn track_outputs(
+        &mut self,
+        transaction: &Transaction,
+        confirmed: bool,
+    ) -> Result<(), error::Error> {
+        let txid = transaction.txid();
+
+        let mut changed = false;
+        for input in &transaction.input {
+            let outpoint = &input.previous_output;
+            if !self.filter_state.is_watched_outpoint(outpoint) {
+                continue;
+            }
+            log::info!("Outpoint {} spent by {}", outpoint, txid);
+            if confirmed {
+                changed |= self.filter_state.remove_outpoint(outpoint);
+                if let Some(list) = self.watch_list.as_mut() {
+                    list.remove_outpoint(outpoint);
+                }
+            }
+        }
+
+        for (vout, output) in transaction.output.iter().enumerate() {
+            if !self.filter_state.is_watched_output(output) {
+                continue;
+            }
+            let outpoint = OutPoint::new(txid, vout as u32);
+            let owner = self.owner_of(output);
+            if self.filter_state.add_outpoint(&outpoint, owner.as_deref()) {
+                log::debug!("Watching outpoint {}", outpoint);
+                changed = true;
+            }
+            if let Some(list) = self.watch_list.as_mut() {
+                changed |= list.insert_outpoint(outpoint, owner);
+            }
+        }
+        if changed {
+            if let Some(list) = self.watch_list.as_ref() {
+                list.save()?;
+            }
+            self.reload_filter(Instant::now())?;
+        }
+        Ok(())
+    }
+
+    /// Extended public key of the account `output` pays to, or data of the
+    /// watched item it pays to.
+    fn owner_of(&self, output: &TxOut) -> Option<String> {
+        let (token, script) = token::split_output(output);
+        if let Some(account) = self.accounts.iter().find(|a| a.index_of(&script).is_some()) {
+            return Some(account.xpub.to_string());
+        }
+        let items = self.watch_list.as_ref()?.items();
+        let item = items.iter().find(|item| match item.kind {
+            WatchKind::CashAddr => {
+                Address::decode(&item.data).is_ok_and(|a| a.script_pubkey() == script)
+            }
+            WatchKind::Category => {
+                let category = token::parse_item(&item.data).and_then(Result::ok);
+                category.is_some_and(|c| token.as_ref().is_some_and(|t| t.category == c))
+            }
+            WatchKind::Script => hex::decode(&item.data).is_ok_and(|s| s == script.as_bytes()),
+            WatchKind::Txid => false,
+        })?;
+
+        Some(item.data.clone())
+    }
+
+    fn add_xpub(
+        &mut self,
+        xpub: &str,
+        path: &str,
+        gap_limit: u32,
+        label: Option<String>,
+        birthday: Option<u64>,
+        ui_show_tx: &Sender<UIMessage>,
+    ) {
+        match self.watch_xpub(xpub, path, gap_limit, label, birthday) {
+            Ok(addresses) if !addresses.is_empty() => {
+                notify(
+                    ui_show_tx,
+                    UIMessage::AccountExtended {
+                        xpub: xpub.to_owned(),
+                        addresses: addresses.iter().map(|a| a.encode()).collect(),
+                    },
+                );
+            }
+            Ok(_) => {}
+            // Bad user input shouldn't stop the watcher.
+            Err(e) => log::warn!("Failed to watch account {}: {}", xpub, e),
+        }
+    }
+
+    /// Mark the accounts' addresses paid by `transaction` as used, and derive
+    /// more addresses to keep the gap limit.
+    fn extend_accounts(
+        &mut self,
+        transaction: &Transaction,
+        ui_show_tx: &Sender<UIMessage>,
+    ) -> Result<(), error::Error> {
+        let mut changed = false;
+
+        for account in self.accounts.iter_mut() {
+            let used = transaction
+                .output
+                .iter()
+                .fold(false, |used, o| account.mark_used(&token::split_output(o).1) | used);
+            if !used {
+                continue;
+            }
+            let addresses = account.extend()?;
+            for address in &addresses {
+                changed |= self.filter_state.watch_address(address);
+            }
+            if let Some(list) = self.watch_list.as_mut() {
+                list.upsert_account(account.record());
+            }
+            if !addresses.is_empty() {
+                log::info!(
+                    "Derived {} more address(es) for account {}",
+                    addresses.len(),
+                    account.xpub
+                );
+                notify(
+                    ui_show_tx,
+                    UIMessage::AccountExtended {
+                        xpub: account.xpub.to_string(),
+                        addresses: addresses.iter().map(|a| a.encode()).collect(),
+                    },
+                );
+            }
+        }
+        if changed {
+            if let Some(list) = self.watch_list.as_ref() {
+                list.save()?;
+            }
+            self.reload_filter(Instant::now())?;
+        }
+        Ok(())
+    }
+
+    fn add_watch_item(
+        &mut self,
+        data: String,
+        label: Option<String>,
+        birthday: Option<u64>,
+    ) -> Result<(), error::Error> {
+        if self.filter_state.add_bloom_item(data.clone())? {
+            self.reload_filter(Instant::now())?;
+        }
+
+        if let (Some(list), Some(kind)) = (self.watch_list.as_mut(), WatchKind::of(&data)) {
+            let item = WatchItem {
+                kind,
+                data,
+                label,
+                birthday: birthday.or(self.ready.then_some(self.tip)),
+            };
+            if list.insert(item) {
+                list.save()?;
+                if birthday.is_some() {
+                    self.catch_up()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send a message to the front-end, which may already be gone when shutting down.
+fn notify(ui_show_tx: &Sender<UIMessage>, msg: UIMessage) {
+    if ui_show_tx.send(msg).is_err() {
+        log::debug!("Front-end disconnected, dropping message");
+    }
+}
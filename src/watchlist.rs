@@ -0,0 +1,362 @@
+//! Persistent list of watched items.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use nakamoto_cash::client::Network;
+use nakamoto_cash::common::bitcoin::OutPoint;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::address::Address;
+use crate::error::Error;
+use crate::hd::HdRecord;
+use crate::scan::Chunk;
+use crate::token;
+
+/// File name of the watch list, stored next to the client's header store.
+pub const FILE_NAME: &str = "watchlist.json";
+
+const TXID_LEN: usize = 32;
+
+/// What a watched item refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchKind {
+    /// A CashAddr encoded address.
+    CashAddr,
+    /// A raw hex encoded script or data push.
+    Script,
+    /// A hex encoded transaction id.
+    Txid,
+    /// A CashToken category, as `token:<category>`.
+    Category,
+}
+
+impl WatchKind {
+    /// Classify user input, or `None` if it is neither a cashaddr, a token
+    /// category nor hex.
+    pub fn of(data: &str) -> Option<Self> {
+        if Address::decode(data).is_ok() {
+            return Some(Self::CashAddr);
+        }
+        if let Some(category) = token::parse_item(data) {
+            return category.is_ok().then_some(Self::Category);
+        }
+        match hex::decode(data) {
+            Ok(bytes) if bytes.len() == TXID_LEN => Some(Self::Txid),
+            Ok(_) => Some(Self::Script),
+            Err(_) => None,
+        }
+    }
+}
+
+/// A single watched item.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchItem {
+    pub kind: WatchKind,
+    /// The item as entered by the user.
+    pub data: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Height of the first block the item may appear in, `None` until the
+    /// client is ready if it wasn't given: the item is then born at the tip.
+    #[serde(default)]
+    pub birthday: Option<u64>,
+}
+
+/// Outpoint of a matched output, as stored in the watch list file.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum OutPointEntry {
+    /// `txid:vout`, and the data of the item or the extended public key of
+    /// the account it pays to.
+    Owned { outpoint: String, owner: String },
+    /// `txid:vout` alone, as written by earlier versions.
+    Bare(String),
+}
+
+/// On-disk representation of the watch list.
+#[derive(Default, Serialize, Deserialize)]
+struct WatchListFile {
+    #[serde(default)]
+    items: Vec<WatchItem>,
+    /// Outpoints of matched outputs.
+    #[serde(default)]
+    outpoints: Vec<OutPointEntry>,
+    /// HD accounts, by extended public key.
+    #[serde(default)]
+    accounts: Vec<HdRecord>,
+    /// Height up to which merkle blocks were scanned for the items.
+    #[serde(default)]
+    scanned_height: Option<u64>,
+    /// Chunks of the rescan in progress, if any.
+    #[serde(default)]
+    scan: Vec<Chunk>,
+}
+
+/// Watch list backed by a JSON file.
+#[derive(Clone, Debug)]
+pub struct WatchList {
+    path: PathBuf,
+    items: Vec<WatchItem>,
+    /// Outpoints, and the item or account they pay to, if known.
+    outpoints: Vec<(OutPoint, Option<String>)>,
+    accounts: Vec<HdRecord>,
+    scanned_height: Option<u64>,
+    scan: Vec<Chunk>,
+}
+
+impl WatchList {
+    /// Default location of the watch list for `network`, under the client `root`.
+    pub fn path(root: &Path, network: Network) -> PathBuf {
+        root.join(".nakamoto").join(network.as_str()).join(FILE_NAME)
+    }
+
+    /// Open the watch list at `path`, starting empty if the file doesn't exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let file = match fs::read_to_string(&path) {
+            Ok(s) => {
+                let mut file =
+                    serde_json::from_str::<Value>(&s).map_err(|_| Error::Deserialization)?;
+                migrate_heights(&mut file);
+                serde_json::from_value::<WatchListFile>(file).map_err(|_| Error::Deserialization)?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => WatchListFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let outpoints = file
+            .outpoints
+            .into_iter()
+            .map(|entry| {
+                let (outpoint, owner) = match entry {
+                    OutPointEntry::Owned { outpoint, owner } => (outpoint, Some(owner)),
+                    OutPointEntry::Bare(outpoint) => (outpoint, None),
+                };
+                let outpoint = OutPoint::from_str(&outpoint).map_err(|_| Error::Deserialization)?;
+                Ok((outpoint, owner))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            path,
+            items: file.items,
+            outpoints,
+            accounts: file.accounts,
+            scanned_height: file.scanned_height,
+            scan: file.scan,
+        })
+    }
+
+    /// Location of the watch list file.
+    pub fn file_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Watched items, in insertion order.
+    pub fn items(&self) -> &[WatchItem] {
+        &self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.accounts.is_empty()
+    }
+
+    /// Add an item, returns `false` if it was already watched.
+    pub fn insert(&mut self, item: WatchItem) -> bool {
+        if self.items.iter().any(|i| i.data == item.data) {
+            return false;
+        }
+        self.rewind(item.birthday);
+        self.items.push(item);
+        true
+    }
+
+    /// Remove an item and its outpoints, returns `false` if it wasn't watched.
+    pub fn remove(&mut self, data: &str) -> bool {
+        let len = self.items.len();
+        self.items.retain(|i| i.data != data);
+        self.remove_outpoints(data);
+        self.items.len() != len
+    }
+
+    /// Outpoints of outputs paying to watched items, and the data of the item
+    /// or the extended public key of the account they pay to, if known.
+    pub fn outpoints(&self) -> impl Iterator<Item = (&OutPoint, Option<&str>)> {
+        self.outpoints.iter().map(|(o, owner)| (o, owner.as_deref()))
+    }
+
+    /// Remember an outpoint paying to `owner`, returns `false` if it was
+    /// already known.
+    pub fn insert_outpoint(&mut self, outpoint: OutPoint, owner: Option<String>) -> bool {
+        match self.outpoints.iter_mut().find(|(o, _)| *o == outpoint) {
+            Some((_, known @ None)) if owner.is_some() => {
+                *known = owner;
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.outpoints.push((outpoint, owner));
+                true
+            }
+        }
+    }
+
+    /// Forget an outpoint, once its spend is confirmed.
+    pub fn remove_outpoint(&mut self, outpoint: &OutPoint) -> bool {
+        let len = self.outpoints.len();
+        self.outpoints.retain(|(o, _)| o != outpoint);
+        self.outpoints.len() != len
+    }
+
+    fn remove_outpoints(&mut self, owner: &str) {
+        self.outpoints.retain(|(_, o)| o.as_deref() != Some(owner));
+    }
+
+    /// Watched HD accounts.
+    pub fn accounts(&self) -> &[HdRecord] {
+        &self.accounts
+    }
+
+    /// Add or update an account, returns `false` if it was already stored as is.
+    pub fn upsert_account(&mut self, record: HdRecord) -> bool {
+        match self.accounts.iter_mut().find(|a| a.xpub == record.xpub) {
+            Some(a) if *a == record => false,
+            Some(a) => {
+                *a = record;
+                true
+            }
+            None => {
+                self.rewind(record.birthday);
+                self.accounts.push(record);
+                true
+            }
+        }
+    }
+
+    /// Remove an account and its outpoints, returns `false` if it wasn't watched.
+    pub fn remove_account(&mut self, xpub: &str) -> bool {
+        let len = self.accounts.len();
+        self.accounts.retain(|a| a.xpub != xpub);
+        self.remove_outpoints(xpub);
+        self.accounts.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.outpoints.clear();
+        self.accounts.clear();
+        self.scanned_height = None;
+        self.scan.clear();
+    }
+
+    /// Height up to which merkle blocks were scanned for the items, if any.
+    pub fn scanned_height(&self) -> Option<u64> {
+        self.scanned_height
+    }
+
+    /// Record that merkle blocks up to `height` were scanned.
+    pub fn set_scanned_height(&mut self, height: u64) {
+        self.scanned_height = Some(height);
+    }
+
+    /// Chunks of the rescan in progress, empty if there is none.
+    pub fn scan(&self) -> &[Chunk] {
+        &self.scan
+    }
+
+    pub fn set_scan(&mut self, chunks: Vec<Chunk>) {
+        self.scan = chunks;
+    }
+
+    /// First height left to scan: past the scanned height, or the earliest
+    /// birthday if nothing was scanned yet. `None` if no item has a birthday.
+    pub fn scan_start(&self) -> Option<u64> {
+        match self.scanned_height {
+            Some(height) => Some(height + 1),
+            None => self.birthdays().min(),
+        }
+    }
+
+    /// Give a birthday to the items and accounts born at the tip, returns
+    /// `false` if they all had one.
+    pub fn set_birthdays(&mut self, tip: u64) -> bool {
+        let items = self.items.iter_mut().map(|i| &mut i.birthday);
+        let accounts = self.accounts.iter_mut().map(|a| &mut a.birthday);
+
+        let mut changed = false;
+        for birthday in items.chain(accounts).filter(|b| b.is_none()) {
+            *birthday = Some(tip);
+            changed = true;
+        }
+        changed
+    }
+
+    fn birthdays(&self) -> impl Iterator<Item = u64> + '_ {
+        let items = self.items.iter().filter_map(|i| i.birthday);
+        let accounts = self.accounts.iter().filter_map(|a| a.birthday);
+
+        items.chain(accounts)
+    }
+
+    /// An item born before the scanned height makes us scan again from its
+    /// birthday.
+    fn rewind(&mut self, birthday: Option<u64>) {
+        if let (Some(birthday), Some(scanned)) = (birthday, self.scanned_height) {
+            if birthday <= scanned {
+                self.scanned_height = birthday.checked_sub(1);
+            }
+        }
+    }
+
+    /// Write the watch list to disk.
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = WatchListFile {
+            items: self.items.clone(),
+            outpoints: self
+                .outpoints
+                .iter()
+                .map(|(outpoint, owner)| match owner {
+                    Some(owner) => OutPointEntry::Owned {
+                        outpoint: outpoint.to_string(),
+                        owner: owner.clone(),
+                    },
+                    None => OutPointEntry::Bare(outpoint.to_string()),
+                })
+                .collect(),
+            accounts: self.accounts.clone(),
+            scanned_height: self.scanned_height,
+            scan: self.scan.clone(),
+        };
+        let json = serde_json::to_string_pretty(&file).map_err(|_| Error::WriteError)?;
+        // Write to a temporary file first, so that a crash never leaves a truncated list.
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// Earlier versions stored the tip height items and accounts were added at as
+/// their `height`, which was 0 for those added before the client was ready.
+/// A 0 height must not make us scan from genesis, and is read as unknown.
+fn migrate_heights(file: &mut Value) {
+    for key in ["items", "accounts"] {
+        let Some(entries) = file.get_mut(key).and_then(Value::as_array_mut) else {
+            continue;
+        };
+        for entry in entries.iter_mut().filter_map(Value::as_object_mut) {
+            let Some(height) = entry.remove("height") else {
+                continue;
+            };
+            if !entry.contains_key("birthday") && height.as_u64().is_some_and(|h| h != 0) {
+                entry.insert("birthday".to_owned(), height);
+            }
+        }
+    }
+}
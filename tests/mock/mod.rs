@@ -0,0 +1,358 @@
+//! A scriptable client handle, to drive the watcher without a network.
+//!
+//! [`MockHandle`] records every [`Command`] it is given and answers header
+//! queries from a chain built with [`MockHandle::mine`]. Events are built with
+//! the helpers below and fed to [`Watcher::handle_client_event`] one at a time,
+//! so tests are deterministic and need no threads.
+//!
+//! Handle methods the watcher doesn't use fail rather than panic: a watcher
+//! starting to call one gets an error in the test instead of a dead thread.
+//!
+//! [`Watcher::handle_client_event`]: p2p_client::Watcher::handle_client_event
+#![allow(dead_code)]
+use std::net;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{self as chan, Receiver, Sender};
+use nakamoto_cash::client::handle::{self, Error};
+use nakamoto_cash::client::traits::Handle;
+use nakamoto_cash::client::{Event, Network, Peer};
+use nakamoto_cash::common::bitcoin::blockdata::constants::genesis_block;
+use nakamoto_cash::common::bitcoin::hashes::Hash;
+use nakamoto_cash::common::bitcoin::network::constants::ServiceFlags;
+use nakamoto_cash::common::bitcoin::network::message::NetworkMessage;
+use nakamoto_cash::common::bitcoin::network::Address as NetAddress;
+use nakamoto_cash::common::bitcoin::util::merkleblock::MerkleBlock;
+use nakamoto_cash::common::bitcoin::{
+    Block, BlockHash, BlockHeader, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn,
+    TxMerkleNode, TxOut, Txid, Witness,
+};
+use nakamoto_cash::common::block::filter::BlockFilter;
+use nakamoto_cash::common::block::time::LocalTime;
+use nakamoto_cash::common::block::tree::{self, ImportResult};
+use nakamoto_cash::common::block::Height;
+use nakamoto_cash::common::nonempty::NonEmpty;
+use nakamoto_cash::net::{Disconnect, Link};
+use nakamoto_cash::p2p::{Command, PeerId};
+use p2p_client::{Address, AddressType, UIMessage, Wallet, WatchList};
+
+/// State shared by the clones of a [`MockHandle`].
+#[derive(Default)]
+struct State {
+    headers: Vec<BlockHeader>,
+    commands: Vec<Command>,
+    submitted: Vec<Transaction>,
+    peers: Vec<PeerId>,
+}
+
+/// A client handle that records commands instead of talking to peers.
+#[derive(Clone)]
+pub struct MockHandle {
+    state: Arc<Mutex<State>>,
+    events: (Sender<Event>, Receiver<Event>),
+}
+
+impl MockHandle {
+    /// A handle whose chain only holds the regtest genesis block.
+    pub fn new() -> Self {
+        let genesis = genesis_block(nakamoto_cash::common::bitcoin::Network::Regtest);
+        let state = State {
+            headers: vec![genesis.header],
+            ..State::default()
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            events: chan::unbounded(),
+        }
+    }
+
+    /// Connect a block holding `txdata` on top of the chain.
+    ///
+    /// Returns the block and its height.
+    pub fn mine(&self, txdata: Vec<Transaction>) -> (Block, Height) {
+        let mut state = self.state.lock().unwrap();
+        let prev = state.headers.last().expect("the chain has a genesis");
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: prev.time + 600,
+                bits: prev.bits,
+                nonce: 0,
+            },
+            txdata,
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("blocks aren't empty");
+        state.headers.push(block.header);
+
+        (block, state.headers.len() as Height - 1)
+    }
+
+    /// Peers transactions are submitted to, set with [`MockHandle::set_peers`].
+    pub fn set_peers(&self, peers: Vec<PeerId>) {
+        self.state.lock().unwrap().peers = peers;
+    }
+
+    /// Commands issued so far, draining them.
+    pub fn take_commands(&self) -> Vec<Command> {
+        std::mem::take(&mut self.state.lock().unwrap().commands)
+    }
+
+    /// Transactions submitted so far.
+    pub fn submitted(&self) -> Vec<Transaction> {
+        self.state.lock().unwrap().submitted.clone()
+    }
+}
+
+impl Handle for MockHandle {
+    fn get_tip(&self) -> Result<(Height, BlockHeader), Error> {
+        let state = self.state.lock().unwrap();
+        let tip = *state.headers.last().expect("the chain has a genesis");
+
+        Ok((state.headers.len() as Height - 1, tip))
+    }
+
+    fn get_block(&self, hash: &BlockHash) -> Result<Option<(Height, BlockHeader)>, Error> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .headers
+            .iter()
+            .enumerate()
+            .find(|(_, h)| h.block_hash() == *hash)
+            .map(|(height, h)| (height as Height, *h)))
+    }
+
+    fn get_block_by_height(&self, height: Height) -> Result<Option<BlockHeader>, Error> {
+        Ok(self.state.lock().unwrap().headers.get(height as usize).copied())
+    }
+
+    fn find_branch(&self, _to: &BlockHash) -> Result<Option<(Height, NonEmpty<BlockHeader>)>, Error> {
+        Ok(None)
+    }
+
+    fn blocks(&self) -> Receiver<(Block, Height)> {
+        chan::never()
+    }
+
+    fn filters(&self) -> Receiver<(BlockFilter, BlockHash, Height)> {
+        chan::never()
+    }
+
+    fn events(&self) -> Receiver<Event> {
+        self.events.1.clone()
+    }
+
+    fn command(&self, cmd: Command) -> Result<(), Error> {
+        self.state.lock().unwrap().commands.push(cmd);
+        Ok(())
+    }
+
+    fn rescan(
+        &self,
+        _range: impl RangeBounds<Height>,
+        _watch: impl Iterator<Item = Script>,
+    ) -> Result<(), Error> {
+        Err(Error::Disconnected)
+    }
+
+    fn watch(&self, _watch: impl Iterator<Item = Script>) -> Result<(), Error> {
+        Err(Error::Disconnected)
+    }
+
+    fn broadcast(
+        &self,
+        _msg: NetworkMessage,
+        _predicate: fn(Peer) -> bool,
+    ) -> Result<Vec<net::SocketAddr>, Error> {
+        Err(Error::Disconnected)
+    }
+
+    fn query(&self, _msg: NetworkMessage) -> Result<Option<net::SocketAddr>, Error> {
+        Ok(None)
+    }
+
+    fn connect(&self, _addr: net::SocketAddr) -> Result<Link, Error> {
+        Err(Error::Disconnected)
+    }
+
+    fn disconnect(&self, _addr: net::SocketAddr) -> Result<(), Error> {
+        Err(Error::Disconnected)
+    }
+
+    fn import_headers(
+        &self,
+        _headers: Vec<BlockHeader>,
+    ) -> Result<Result<ImportResult, tree::Error>, Error> {
+        Err(Error::Disconnected)
+    }
+
+    fn import_addresses(&self, _addrs: Vec<NetAddress>) -> Result<(), Error> {
+        Err(Error::Disconnected)
+    }
+
+    fn submit_transaction(&self, tx: Transaction) -> Result<NonEmpty<net::SocketAddr>, Error> {
+        let mut state = self.state.lock().unwrap();
+        let peers = NonEmpty::from_vec(state.peers.clone()).ok_or(handle::Error::Disconnected)?;
+        state.submitted.push(tx);
+
+        Ok(peers)
+    }
+
+    fn get_submitted_transaction(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.submitted.iter().find(|tx| tx.txid() == *txid).cloned())
+    }
+
+    fn wait<F: FnMut(Event) -> Option<T>, T>(&self, _f: F) -> Result<T, Error> {
+        Err(Error::Timeout)
+    }
+
+    fn wait_for_peers(
+        &self,
+        _count: usize,
+        _required_services: impl Into<ServiceFlags>,
+    ) -> Result<Vec<(net::SocketAddr, Height, ServiceFlags)>, Error> {
+        Err(Error::Timeout)
+    }
+
+    fn wait_for_height(&self, _h: Height) -> Result<BlockHash, Error> {
+        Err(Error::Timeout)
+    }
+
+    fn shutdown(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A peer address, distinct for every `n`.
+pub fn peer(n: u8) -> PeerId {
+    ([10, 0, 0, n], 8333).into()
+}
+
+/// A chipnet P2PKH address, distinct for every `n`.
+pub fn address(n: u8) -> Address {
+    Address {
+        prefix: "bchtest".to_owned(),
+        kind: AddressType::P2pkh,
+        token_aware: false,
+        hash: vec![n; 20],
+    }
+}
+
+/// A transaction spending `input`, paying each `(script, value)` output.
+pub fn transaction(input: OutPoint, outputs: &[(Script, u64)]) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: input,
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::default(),
+        }],
+        output: outputs
+            .iter()
+            .map(|(script, value)| TxOut {
+                value: *value,
+                script_pubkey: script.clone(),
+            })
+            .collect(),
+    }
+}
+
+/// A coinbase-like outpoint that no watched output refers to, distinct for
+/// every `n`.
+pub fn outpoint(n: u8) -> OutPoint {
+    OutPoint::new(Txid::from_inner([n; 32]), 0)
+}
+
+/// An empty watch list in a temporary directory of its own, named after the
+/// test using it.
+pub fn watch_list(name: &str) -> WatchList {
+    let dir = std::env::temp_dir()
+        .join("p2p-client-tests")
+        .join(format!("{}-{}", name, std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+
+    WatchList::open(dir.join(p2p_client::watchlist::FILE_NAME)).unwrap()
+}
+
+/// A plain chipnet wallet with a fixed key, in a temporary directory of its
+/// own named after the test using it.
+pub fn wallet(name: &str) -> Wallet {
+    let dir = std::env::temp_dir()
+        .join("p2p-client-tests")
+        .join(format!("{}-{}", name, std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Master key of the seed 0x00..0x1f.
+    let xpriv = "tprv8ZgxMBicQKsPe48qChGvN9oKqP6PP2Ecaso2tZ254CTd85JyX3dPfaWw3vWN4wgQeaNrX8ZfK3Zq2Q5LHvEoyfZv3mmJpyUz1caSFLya1Ca";
+    let path = dir.join("wallet.json");
+    std::fs::write(
+        &path,
+        format!(
+            r#"{{"version":1,"network":"chipnet","xpriv":"{}","receive":1,"change":0}}"#,
+            xpriv
+        ),
+    )
+    .unwrap();
+
+    Wallet::open(&path, Network::Chipnet, None).unwrap()
+}
+
+/// A merkle block of `block` matching the transactions in `txids`.
+pub fn merkle_block(block: &Block, txids: &[Txid]) -> MerkleBlock {
+    MerkleBlock::from_block_with_predicate(block, |txid| txids.contains(txid))
+}
+
+pub fn ready(tip: Height) -> Event {
+    Event::Ready {
+        tip,
+        filter_tip: 0,
+        time: LocalTime::from_secs(0),
+    }
+}
+
+pub fn peer_negotiated(addr: PeerId) -> Event {
+    Event::PeerNegotiated {
+        addr,
+        link: Link::Outbound,
+        services: ServiceFlags::NETWORK | ServiceFlags::BLOOM,
+        height: 0,
+        user_agent: "/mock/".to_owned(),
+        version: 70016,
+    }
+}
+
+pub fn peer_disconnected(addr: PeerId) -> Event {
+    Event::PeerDisconnected {
+        addr,
+        reason: Disconnect::ConnectionError(Arc::new(std::io::ErrorKind::ConnectionReset.into())),
+    }
+}
+
+pub fn peer_loaded_bloom_filter(peer: PeerId) -> Event {
+    Event::PeerLoadedBloomFilter { peer }
+}
+
+pub fn merkle_block_received(peer: PeerId, merkle_block: MerkleBlock, height: Height) -> Event {
+    Event::ReceivedMerkleBlock {
+        peer,
+        merkle_block,
+        height,
+    }
+}
+
+pub fn matched_tx(transaction: Transaction) -> Event {
+    Event::ReceivedMatchedTx { transaction }
+}
+
+/// Messages the watcher sent to its front-end so far.
+pub fn drain(ui_show_rx: &Receiver<UIMessage>) -> Vec<UIMessage> {
+    ui_show_rx.try_iter().collect()
+}
@@ -0,0 +1,1054 @@
+//! End-to-end watcher tests against a scripted client handle.
+mod mock;
+
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{self as chan, Receiver, Sender};
+use nakamoto_cash::client::Network;
+use nakamoto_cash::common::bitcoin::blockdata::script::Instruction;
+use nakamoto_cash::common::bitcoin::consensus::encode::{deserialize, serialize};
+use nakamoto_cash::common::bitcoin::secp256k1::ecdsa::Signature;
+use nakamoto_cash::common::bitcoin::secp256k1::{Message, PublicKey, Secp256k1};
+use nakamoto_cash::common::bitcoin::{Block, OutPoint, Script, Transaction};
+use nakamoto_cash::p2p::{Command, PeerId};
+use p2p_client::{broadcast, scan, wallet};
+use p2p_client::{
+    Category, Error, MerkleScanRange, PeerStatus, Shutdown, TokenData, UIMessage, Utxo, WatchItem,
+    WatchKind, WatchList, Watcher,
+};
+
+use mock::MockHandle;
+
+fn setup() -> (MockHandle, Watcher<MockHandle>, Sender<UIMessage>, Receiver<UIMessage>) {
+    let handle = MockHandle::new();
+    let watcher = Watcher::new(handle.clone(), Network::Chipnet);
+    let (ui_show_tx, ui_show_rx) = chan::unbounded();
+
+    (handle, watcher, ui_show_tx, ui_show_rx)
+}
+
+/// Negotiate `peers` and load a filter watching an address onto them.
+fn load_filter_onto(watcher: &mut Watcher<MockHandle>, ui_tx: &Sender<UIMessage>, peers: &[u8]) {
+    for n in peers {
+        watcher
+            .handle_client_event(mock::peer_negotiated(mock::peer(*n)), ui_tx)
+            .unwrap();
+    }
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(mock::address(1).encode()), ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::SendLoadFilter, ui_tx)
+        .unwrap();
+}
+
+/// Mine `n` blocks matching nothing, the block at height `h` is at `h - 1`.
+fn mine(handle: &MockHandle, n: u8) -> Vec<Block> {
+    (0..n)
+        .map(|i| handle.mine(vec![mock::transaction(mock::outpoint(100 + i), &[])]).0)
+        .collect()
+}
+
+/// Feed the watcher the merkle block at `height`, as sent by `peer`.
+fn receive(
+    watcher: &mut Watcher<MockHandle>,
+    ui_tx: &Sender<UIMessage>,
+    peer: u8,
+    blocks: &[Block],
+    height: u64,
+) {
+    let merkle_block = mock::merkle_block(&blocks[height as usize - 1], &[]);
+    watcher
+        .handle_client_event(
+            mock::merkle_block_received(mock::peer(peer), merkle_block, height),
+            ui_tx,
+        )
+        .unwrap();
+}
+
+/// Rescans issued so far, as `(from, to, peers)`.
+fn rescans(handle: &MockHandle) -> Vec<(u64, u64, Vec<PeerId>)> {
+    handle
+        .take_commands()
+        .into_iter()
+        .filter_map(|cmd| match cmd {
+            Command::MerkleBlockRescan {
+                from: Bound::Included(from),
+                to: Bound::Included(to),
+                peers,
+            } => Some((from, to, peers)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn filter_is_loaded_onto_negotiated_peers() {
+    let (handle, watcher, ui_tx, ui_rx) = setup();
+    let mut watcher = watcher.auto_load_filter(true);
+
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(mock::address(1).encode()), &ui_tx)
+        .unwrap();
+    assert!(handle.take_commands().is_empty(), "no peer to load the filter onto yet");
+
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+    match handle.take_commands().as_slice() {
+        [Command::LoadBloomFilter((_, peers))] => assert_eq!(peers, &vec![mock::peer(1)]),
+        cmds => panic!("expected a single filter load, got {} command(s)", cmds.len()),
+    }
+
+    watcher
+        .handle_client_event(mock::peer_loaded_bloom_filter(mock::peer(1)), &ui_tx)
+        .unwrap();
+    assert!(matches!(
+        mock::drain(&ui_rx).as_slice(),
+        [UIMessage::PeerLoadedFilter(peer)] if *peer == mock::peer(1)
+    ));
+}
+
+#[test]
+fn filter_is_not_loaded_until_asked() {
+    let (handle, mut watcher, ui_tx, _ui_rx) = setup();
+
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(2)), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(mock::address(1).encode()), &ui_tx)
+        .unwrap();
+    assert!(handle.take_commands().is_empty());
+
+    watcher
+        .handle_user_input(UIMessage::SendLoadFilter, &ui_tx)
+        .unwrap();
+    match handle.take_commands().as_slice() {
+        [Command::LoadBloomFilter((_, peers))] => {
+            assert_eq!(peers, &vec![mock::peer(1), mock::peer(2)])
+        }
+        cmds => panic!("expected a single filter load, got {} command(s)", cmds.len()),
+    }
+}
+
+#[test]
+fn rescan_skips_disconnected_peers() {
+    let (handle, mut watcher, ui_tx, _ui_rx) = setup();
+
+    for n in [1, 2] {
+        watcher
+            .handle_client_event(mock::peer_negotiated(mock::peer(n)), &ui_tx)
+            .unwrap();
+    }
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(mock::address(1).encode()), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::SendLoadFilter, &ui_tx)
+        .unwrap();
+    watcher
+        .handle_client_event(mock::peer_disconnected(mock::peer(2)), &ui_tx)
+        .unwrap();
+    assert_eq!(watcher.filter_state().peers(), &[mock::peer(1)]);
+    handle.take_commands();
+
+    watcher
+        .handle_user_input(UIMessage::RequestBlocks(MerkleScanRange::new(1, 5)), &ui_tx)
+        .unwrap();
+    match handle.take_commands().as_slice() {
+        [Command::MerkleBlockRescan { from, to, peers }] => {
+            assert_eq!(from, &Bound::Included(1));
+            assert_eq!(to, &Bound::Included(5));
+            assert_eq!(peers, &vec![mock::peer(1)]);
+        }
+        cmds => panic!("expected a single rescan, got {} command(s)", cmds.len()),
+    }
+}
+
+#[test]
+fn rescan_waits_for_a_filtered_peer() {
+    let (handle, mut watcher, ui_tx, _ui_rx) = setup();
+
+    watcher
+        .handle_user_input(UIMessage::RequestBlocks(MerkleScanRange::new(1, 5)), &ui_tx)
+        .unwrap();
+    assert!(handle.take_commands().is_empty());
+
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(mock::address(1).encode()), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::SendLoadFilter, &ui_tx)
+        .unwrap();
+    handle.take_commands();
+
+    watcher.handle_tick(Instant::now(), &ui_tx).unwrap();
+    match handle.take_commands().as_slice() {
+        [Command::MerkleBlockRescan { peers, .. }] => assert_eq!(peers, &vec![mock::peer(1)]),
+        cmds => panic!("expected a single rescan, got {} command(s)", cmds.len()),
+    }
+    watcher.handle_tick(Instant::now(), &ui_tx).unwrap();
+    assert!(handle.take_commands().is_empty(), "the rescan is only retried once");
+}
+
+#[test]
+fn filter_is_refreshed_periodically() {
+    let (handle, watcher, ui_tx, _ui_rx) = setup();
+    let mut watcher = watcher.filter_refresh(Duration::from_secs(60));
+    let start = Instant::now();
+
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(mock::address(1).encode()), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::SendLoadFilter, &ui_tx)
+        .unwrap();
+    handle.take_commands();
+
+    watcher.handle_tick(start, &ui_tx).unwrap();
+    assert!(handle.take_commands().is_empty());
+
+    watcher.handle_tick(start + Duration::from_secs(61), &ui_tx).unwrap();
+    assert!(matches!(
+        handle.take_commands().as_slice(),
+        [Command::LoadBloomFilter(_)]
+    ));
+}
+
+#[test]
+fn run_stops_when_the_client_goes_away() {
+    let (_handle, mut watcher, ui_tx, _ui_rx) = setup();
+    let (_ui_input_tx, ui_input_rx) = chan::unbounded();
+    let (events_tx, events_rx) = chan::unbounded();
+
+    events_tx.send(mock::peer_negotiated(mock::peer(1))).unwrap();
+    drop(events_tx);
+
+    watcher.run(&events_rx, &ui_input_rx, &ui_tx).unwrap();
+    assert_eq!(watcher.filter_state().peers(), &[mock::peer(1)]);
+}
+
+#[test]
+fn run_stops_on_shutdown() {
+    let (_handle, watcher, ui_tx, _ui_rx) = setup();
+    let shutdown = Shutdown::new();
+    let mut watcher = watcher.with_shutdown(shutdown.clone());
+    let (_ui_input_tx, ui_input_rx) = chan::unbounded();
+    let (_events_tx, events_rx) = chan::unbounded();
+
+    shutdown.trigger();
+    assert!(shutdown.is_triggered());
+    watcher.run(&events_rx, &ui_input_rx, &ui_tx).unwrap();
+}
+
+#[test]
+fn proven_payment_is_confirmed() {
+    let (handle, mut watcher, ui_tx, ui_rx) = setup();
+    let address = mock::address(1);
+
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(address.encode()), &ui_tx)
+        .unwrap();
+    let tx = mock::transaction(mock::outpoint(1), &[(address.script_pubkey(), 10_000)]);
+    let (block, height) = handle.mine(vec![tx.clone()]);
+
+    watcher
+        .handle_client_event(
+            mock::merkle_block_received(mock::peer(1), mock::merkle_block(&block, &[tx.txid()]), height),
+            &ui_tx,
+        )
+        .unwrap();
+    watcher
+        .handle_client_event(mock::matched_tx(tx.clone()), &ui_tx)
+        .unwrap();
+
+    let msgs = mock::drain(&ui_rx);
+    assert!(msgs.iter().any(|m| matches!(m, UIMessage::ReceivedBlock(h) if *h == height)));
+    assert!(msgs.iter().any(|m| matches!(
+        m,
+        UIMessage::ReceivedMatchedTx { transaction, confirmation: Some(c), .. }
+            if transaction.txid() == tx.txid() && c.height == height
+    )));
+    assert!(msgs.iter().any(|m| matches!(
+        m,
+        UIMessage::BalanceUpdated { address: a, balance, .. }
+            if *a == address.encode() && balance.confirmed == 10_000
+    )));
+    assert!(watcher.txids().contains(&tx.txid()));
+}
+
+#[test]
+fn spend_clears_balance() {
+    let (_handle, mut watcher, ui_tx, ui_rx) = setup();
+    let address = mock::address(1);
+
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(address.encode()), &ui_tx)
+        .unwrap();
+    let funding = mock::transaction(mock::outpoint(1), &[(address.script_pubkey(), 10_000)]);
+    watcher
+        .handle_client_event(mock::matched_tx(funding.clone()), &ui_tx)
+        .unwrap();
+    assert_eq!(watcher.utxos().total().unconfirmed, 10_000);
+
+    // The spend only pays elsewhere, it matches through the funding outpoint.
+    let spend = mock::transaction(
+        OutPoint::new(funding.txid(), 0),
+        &[(mock::address(2).script_pubkey(), 9_000)],
+    );
+    watcher
+        .handle_client_event(mock::matched_tx(spend.clone()), &ui_tx)
+        .unwrap();
+
+    assert_eq!(watcher.utxos().total().total(), 0);
+    assert!(mock::drain(&ui_rx).iter().any(|m| matches!(
+        m,
+        UIMessage::ReceivedMatchedTx { transaction, confirmation: None, .. }
+            if transaction.txid() == spend.txid()
+    )));
+}
+
+#[test]
+fn spend_before_funding_clears_balance() {
+    let (_handle, mut watcher, ui_tx, _ui_rx) = setup();
+    let address = mock::address(1);
+
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(address.encode()), &ui_tx)
+        .unwrap();
+    let funding = mock::transaction(mock::outpoint(1), &[(address.script_pubkey(), 10_000)]);
+    // Change back to us makes the spend match on its own.
+    let spend = mock::transaction(
+        OutPoint::new(funding.txid(), 0),
+        &[(address.script_pubkey(), 1_000)],
+    );
+    for tx in [&spend, &spend, &funding, &funding] {
+        watcher
+            .handle_client_event(mock::matched_tx(tx.clone()), &ui_tx)
+            .unwrap();
+    }
+
+    assert_eq!(watcher.utxos().total().unconfirmed, 1_000);
+}
+
+#[test]
+fn outpoints_of_a_removed_item_are_pruned() {
+    let (_handle, watcher, ui_tx, _ui_rx) = setup();
+    let mut watcher = watcher
+        .with_watch_list(mock::watch_list("pruned-outpoints"))
+        .unwrap();
+    let (kept, removed) = (mock::address(1), mock::address(2));
+
+    for address in [&kept, &removed] {
+        watcher
+            .handle_user_input(UIMessage::AddBloomItem(address.encode()), &ui_tx)
+            .unwrap();
+    }
+    let funding = mock::transaction(
+        mock::outpoint(1),
+        &[(kept.script_pubkey(), 1_000), (removed.script_pubkey(), 2_000)],
+    );
+    watcher
+        .handle_client_event(mock::matched_tx(funding.clone()), &ui_tx)
+        .unwrap();
+    watcher
+        .handle_user_input(UIMessage::RemoveBloomItem(removed.encode()), &ui_tx)
+        .unwrap();
+
+    let (kept, removed) = (OutPoint::new(funding.txid(), 0), OutPoint::new(funding.txid(), 1));
+    assert!(watcher.filter_state().is_watched_outpoint(&kept));
+    assert!(!watcher.filter_state().is_watched_outpoint(&removed));
+
+    let list = WatchList::open(watcher.watch_list().unwrap().file_path()).unwrap();
+    let outpoints = list.outpoints().map(|(o, _)| *o).collect::<Vec<_>>();
+    assert_eq!(outpoints, vec![kept]);
+}
+
+#[test]
+fn outpoint_is_pruned_once_its_spend_is_confirmed() {
+    let (handle, watcher, ui_tx, _ui_rx) = setup();
+    let mut watcher = watcher
+        .with_watch_list(mock::watch_list("spent-outpoints"))
+        .unwrap();
+    let address = mock::address(1);
+
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(address.encode()), &ui_tx)
+        .unwrap();
+    let funding = mock::transaction(mock::outpoint(1), &[(address.script_pubkey(), 10_000)]);
+    let outpoint = OutPoint::new(funding.txid(), 0);
+    let spend = mock::transaction(outpoint, &[(mock::address(2).script_pubkey(), 9_000)]);
+
+    // The spend stays watched until it is confirmed.
+    for tx in [&funding, &spend] {
+        watcher
+            .handle_client_event(mock::matched_tx(tx.clone()), &ui_tx)
+            .unwrap();
+    }
+    assert!(watcher.filter_state().is_watched_outpoint(&outpoint));
+
+    let (block, height) = handle.mine(vec![spend.clone()]);
+    watcher
+        .handle_client_event(
+            mock::merkle_block_received(
+                mock::peer(1),
+                mock::merkle_block(&block, &[spend.txid()]),
+                height,
+            ),
+            &ui_tx,
+        )
+        .unwrap();
+    watcher
+        .handle_client_event(mock::matched_tx(spend.clone()), &ui_tx)
+        .unwrap();
+
+    assert!(!watcher.filter_state().is_watched_outpoint(&outpoint));
+    assert_eq!(watcher.watch_list().unwrap().outpoints().count(), 0);
+    assert_eq!(watcher.utxos().total().total(), 0);
+}
+
+#[test]
+fn unrelated_transaction_is_ignored() {
+    let (_handle, mut watcher, ui_tx, ui_rx) = setup();
+
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(mock::address(1).encode()), &ui_tx)
+        .unwrap();
+    // A false positive of the filter, or a peer making things up.
+    let tx = mock::transaction(mock::outpoint(1), &[(mock::address(2).script_pubkey(), 10_000)]);
+    watcher
+        .handle_client_event(mock::matched_tx(tx), &ui_tx)
+        .unwrap();
+
+    assert!(watcher.txids().is_empty());
+    assert!(mock::drain(&ui_rx).is_empty());
+}
+
+#[test]
+fn merkle_block_off_the_chain_is_rejected() {
+    let (handle, mut watcher, ui_tx, ui_rx) = setup();
+    let tx = mock::transaction(mock::outpoint(1), &[(mock::address(1).script_pubkey(), 10_000)]);
+    handle.mine(vec![tx.clone()]);
+
+    // Same height, different block.
+    let other = MockHandle::new();
+    let fork = mock::transaction(mock::outpoint(2), &[(mock::address(1).script_pubkey(), 10_000)]);
+    let (block, height) = other.mine(vec![fork.clone()]);
+
+    watcher
+        .handle_client_event(
+            mock::merkle_block_received(mock::peer(1), mock::merkle_block(&block, &[fork.txid()]), height),
+            &ui_tx,
+        )
+        .unwrap();
+    assert!(mock::drain(&ui_rx).is_empty());
+}
+
+#[test]
+fn token_category_is_matched() {
+    let (_handle, mut watcher, ui_tx, ui_rx) = setup();
+    let category = Category([7; 32]);
+
+    watcher
+        .handle_user_input(UIMessage::AddBloomItem(format!("token:{}", category)), &ui_tx)
+        .unwrap();
+
+    // 100 fungible tokens, paid to an address we don't watch.
+    let mut script = vec![0xef];
+    script.extend(category.as_bytes());
+    script.extend([0x20, 100]);
+    script.extend(mock::address(2).script_pubkey().as_bytes());
+    let tx = mock::transaction(mock::outpoint(1), &[(Script::from(script), 1_000)]);
+
+    watcher
+        .handle_client_event(mock::matched_tx(tx.clone()), &ui_tx)
+        .unwrap();
+
+    let expected = TokenData {
+        category,
+        amount: 100,
+        nft: None,
+    };
+    assert!(mock::drain(&ui_rx).iter().any(|m| matches!(
+        m,
+        UIMessage::ReceivedMatchedTx { tokens, .. } if tokens.as_slice() == [(0, expected.clone())]
+    )));
+    assert!(watcher.txids().contains(&tx.txid()));
+}
+
+#[test]
+fn broadcast_waits_for_a_peer() {
+    let (handle, mut watcher, ui_tx, ui_rx) = setup();
+    let tx = mock::transaction(mock::outpoint(1), &[(mock::address(1).script_pubkey(), 10_000)]);
+    handle.set_peers(vec![mock::peer(1)]);
+
+    watcher
+        .handle_user_input(UIMessage::BroadcastTx(hex::encode(serialize(&tx))), &ui_tx)
+        .unwrap();
+    assert!(handle.submitted().is_empty());
+
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+    assert_eq!(handle.submitted(), vec![tx.clone()]);
+
+    watcher
+        .handle_client_event(mock::peer_disconnected(mock::peer(1)), &ui_tx)
+        .unwrap();
+    let statuses = mock::drain(&ui_rx)
+        .into_iter()
+        .filter_map(|m| match m {
+            UIMessage::TxBroadcast { txid, peer, status } if txid == tx.txid() => Some((peer, status)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        vec![
+            (mock::peer(1), PeerStatus::Submitted),
+            (mock::peer(1), PeerStatus::Dropped)
+        ]
+    );
+}
+
+#[test]
+fn broadcast_is_submitted_again_once_no_peer_takes_it() {
+    let (handle, mut watcher, ui_tx, ui_rx) = setup();
+    let tx = mock::transaction(mock::outpoint(1), &[(mock::address(1).script_pubkey(), 10_000)]);
+    handle.set_peers(vec![mock::peer(1)]);
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+
+    watcher
+        .handle_user_input(UIMessage::BroadcastTx(hex::encode(serialize(&tx))), &ui_tx)
+        .unwrap();
+    assert_eq!(handle.submitted().len(), 1);
+    watcher
+        .handle_client_event(mock::peer_disconnected(mock::peer(1)), &ui_tx)
+        .unwrap();
+    handle.set_peers(vec![mock::peer(2)]);
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(2)), &ui_tx)
+        .unwrap();
+    assert_eq!(handle.submitted().len(), 2, "submitted again to the new peer");
+
+    watcher
+        .handle_tick(Instant::now() + broadcast::ACK_TIMEOUT, &ui_tx)
+        .unwrap();
+    assert!(watcher.broadcasts()[&tx.txid()].is_pending());
+    assert!(mock::drain(&ui_rx).iter().any(|m| matches!(
+        m,
+        UIMessage::TxBroadcast { peer, status: PeerStatus::Rejected, .. } if *peer == mock::peer(2)
+    )));
+
+    handle.set_peers(vec![mock::peer(3)]);
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(3)), &ui_tx)
+        .unwrap();
+    assert_eq!(handle.submitted().len(), 3);
+}
+
+/// An unspent output of `value` satoshis paying to `script`.
+fn coin(n: u8, script: &Script, value: u64, height: Option<u64>) -> Utxo {
+    Utxo {
+        outpoint: mock::outpoint(n),
+        value,
+        script_pubkey: script.clone(),
+        token: None,
+        height,
+    }
+}
+
+#[test]
+fn coins_are_spent_confirmed_first_then_largest_first() {
+    let mut wallet = mock::wallet("coin-selection");
+    let mine = wallet.receive_address().unwrap().script_pubkey();
+    let coins = [
+        coin(1, &mine, 5_000, Some(1)),
+        coin(2, &mine, 20_000, Some(2)),
+        coin(3, &mine, 50_000, None),
+        coin(4, &mock::address(8).script_pubkey(), 100_000, Some(3)),
+    ];
+    let inputs = |tx: &Transaction| {
+        tx.input
+            .iter()
+            .map(|i| i.previous_output)
+            .collect::<Vec<_>>()
+    };
+    let recipient = mock::address(9);
+
+    // One input and two outputs weigh 226 bytes.
+    let tx = wallet
+        .build_transaction(&coins, &[(recipient.clone(), 10_000)], 1)
+        .unwrap();
+    assert_eq!(inputs(&tx), vec![mock::outpoint(2)]);
+    assert_eq!(tx.output[0].value, 10_000);
+    assert_eq!(tx.output[0].script_pubkey, recipient.script_pubkey());
+    assert_eq!(tx.output[1].value, 20_000 - 10_000 - 226);
+    assert!(wallet.is_mine(&tx.output[1].script_pubkey));
+
+    let tx = wallet
+        .build_transaction(&coins, &[(recipient.clone(), 24_000)], 1)
+        .unwrap();
+    assert_eq!(inputs(&tx), vec![mock::outpoint(2), mock::outpoint(1)]);
+    assert_eq!(tx.output[1].value, 25_000 - 24_000 - 374);
+
+    // Coins of other scripts are never spent.
+    assert!(matches!(
+        wallet.build_transaction(&coins, &[(recipient, 80_000)], 1),
+        Err(Error::InsufficientFunds {
+            needed: 80_522,
+            available: 75_000
+        })
+    ));
+}
+
+#[test]
+fn signature_hash_matches_the_bip143_vector() {
+    // Second input of the native P2WPKH example of BIP143, whose digest is
+    // the one Bitcoin Cash signs with the fork id bit set.
+    let raw = "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+    let tx: Transaction = deserialize(&hex::decode(raw).unwrap()).unwrap();
+    let script_code =
+        Script::from(hex::decode("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").unwrap());
+
+    assert_eq!(
+        hex::encode(wallet::signature_hash(&tx, 1, &script_code, 600_000_000, 0x01)),
+        "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+    );
+    assert_eq!(
+        hex::encode(wallet::signature_hash(
+            &tx,
+            1,
+            &script_code,
+            600_000_000,
+            wallet::SIGHASH_ALL_FORKID
+        )),
+        "467f411d178762db122a6aced76370a1c8324355bf0796502bf82eeaeda86a35"
+    );
+}
+
+#[test]
+fn inputs_are_signed_with_the_fork_id() {
+    let mut wallet = mock::wallet("signed-inputs");
+    let mine = wallet.receive_address().unwrap().script_pubkey();
+    let coins = [coin(1, &mine, 5_000, Some(1)), coin(2, &mine, 6_000, Some(1))];
+    let tx = wallet
+        .build_transaction(&coins, &[(mock::address(9), 10_000)], 1)
+        .unwrap();
+    let secp = Secp256k1::verification_only();
+
+    assert_eq!(tx.input.len(), 2);
+    for (index, input) in tx.input.iter().enumerate() {
+        let pushes = input
+            .script_sig
+            .instructions()
+            .map(|i| match i.unwrap() {
+                Instruction::PushBytes(bytes) => bytes.to_vec(),
+                Instruction::Op(op) => panic!("unexpected {:?}", op),
+            })
+            .collect::<Vec<_>>();
+        let [signature, public_key] = pushes.as_slice() else {
+            panic!("expected a signature and a public key");
+        };
+        let (hash_type, der) = signature.split_last().unwrap();
+        assert_eq!(u32::from(*hash_type), wallet::SIGHASH_ALL_FORKID);
+
+        let coin = coins.iter().find(|c| c.outpoint == input.previous_output).unwrap();
+        let digest =
+            wallet::signature_hash(&tx, index, &mine, coin.value, wallet::SIGHASH_ALL_FORKID);
+        secp.verify_ecdsa(
+            &Message::from_slice(&digest).unwrap(),
+            &Signature::from_der(der).unwrap(),
+            &PublicKey::from_slice(public_key).unwrap(),
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn wallet_file_is_only_readable_by_its_owner() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut wallet = mock::wallet("private-wallet");
+    wallet.new_address().unwrap();
+
+    let mode = std::fs::metadata(wallet.path()).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn payment_inputs_are_reserved_until_no_peer_takes_it() {
+    let (handle, watcher, ui_tx, ui_rx) = setup();
+    let wallet = mock::wallet("reserved-inputs");
+    let mine = wallet.receive_address().unwrap().script_pubkey();
+    let mut watcher = watcher.with_wallet(wallet);
+    handle.set_peers(vec![mock::peer(1)]);
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+    let funding = mock::transaction(mock::outpoint(1), &[(mine, 20_000)]);
+    watcher
+        .handle_client_event(mock::matched_tx(funding.clone()), &ui_tx)
+        .unwrap();
+    let outpoint = OutPoint::new(funding.txid(), 0);
+    let pay = || UIMessage::SendPayment {
+        address: mock::address(9).encode(),
+        amount: 10_000,
+        fee_rate: 1,
+    };
+
+    watcher.handle_user_input(pay(), &ui_tx).unwrap();
+    assert!(watcher.utxos().is_reserved(&outpoint));
+
+    // The only coin is spent by the first payment.
+    mock::drain(&ui_rx);
+    watcher.handle_user_input(pay(), &ui_tx).unwrap();
+    assert_eq!(handle.submitted().len(), 1);
+    assert!(mock::drain(&ui_rx).iter().any(|m| matches!(m, UIMessage::BroadcastFailed(_))));
+
+    // Once no peer takes it, the coin can be spent again.
+    watcher
+        .handle_tick(Instant::now() + broadcast::ACK_TIMEOUT, &ui_tx)
+        .unwrap();
+    assert!(!watcher.utxos().is_reserved(&outpoint));
+    watcher.handle_user_input(pay(), &ui_tx).unwrap();
+    let submitted = handle.submitted();
+    assert_eq!(submitted.len(), 2);
+    assert_eq!(submitted[1].input[0].previous_output, outpoint);
+}
+
+#[test]
+fn ready_scans_from_the_earliest_birthday() {
+    let (handle, watcher, ui_tx, _ui_rx) = setup();
+    let mut watcher = watcher
+        .auto_load_filter(true)
+        .with_watch_list(mock::watch_list("earliest-birthday"))
+        .unwrap();
+
+    for (n, birthday) in [(1, Some(7)), (2, Some(4)), (3, None)] {
+        watcher
+            .handle_user_input(
+                UIMessage::AddWatchItem {
+                    data: mock::address(n).encode(),
+                    label: None,
+                    birthday,
+                },
+                &ui_tx,
+            )
+            .unwrap();
+    }
+    watcher
+        .handle_client_event(mock::peer_negotiated(mock::peer(1)), &ui_tx)
+        .unwrap();
+    handle.take_commands();
+
+    watcher
+        .handle_client_event(mock::ready(10), &ui_tx)
+        .unwrap();
+    match handle.take_commands().as_slice() {
+        [Command::MerkleBlockRescan { from, to, peers }] => {
+            assert_eq!(from, &Bound::Included(4));
+            assert_eq!(to, &Bound::Included(10));
+            assert_eq!(peers, &vec![mock::peer(1)]);
+        }
+        cmds => panic!("expected a single rescan, got {} command(s)", cmds.len()),
+    }
+    let birthdays = watcher
+        .watch_list()
+        .unwrap()
+        .items()
+        .iter()
+        .map(|i| i.birthday)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        birthdays,
+        vec![Some(7), Some(4), Some(10)],
+        "items are born at the tip by default"
+    );
+}
+
+#[test]
+fn legacy_zero_height_is_an_unknown_birthday() {
+    let path = mock::watch_list("legacy-height").file_path().to_owned();
+    let items = [(mock::address(1), 0), (mock::address(2), 7)]
+        .iter()
+        .map(|(a, h)| format!(r#"{{"kind":"cashaddr","data":"{}","height":{}}}"#, a.encode(), h))
+        .collect::<Vec<_>>();
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let account = r#"{"xpub":"xpub","path":"m","gap_limit":20,"height":0}"#;
+    std::fs::write(
+        &path,
+        format!(r#"{{"items":[{}],"accounts":[{}]}}"#, items.join(","), account),
+    )
+    .unwrap();
+
+    let list = WatchList::open(&path).unwrap();
+    let birthdays = list.items().iter().map(|i| i.birthday).collect::<Vec<_>>();
+    assert_eq!(birthdays, vec![None, Some(7)]);
+    assert_eq!(list.accounts()[0].birthday, None);
+}
+
+#[test]
+fn genesis_birthday_is_kept() {
+    let mut list = mock::watch_list("genesis-birthday");
+    list.insert(WatchItem {
+        kind: WatchKind::CashAddr,
+        data: mock::address(1).encode(),
+        label: None,
+        birthday: Some(0),
+    });
+    list.save().unwrap();
+
+    let list = WatchList::open(list.file_path()).unwrap();
+    assert_eq!(list.items()[0].birthday, Some(0));
+    assert_eq!(list.scan_start(), Some(0));
+}
+
+#[test]
+fn unreadable_account_is_skipped() {
+    let path = mock::watch_list("unreadable-account").file_path().to_owned();
+    let address = mock::address(1);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(
+        &path,
+        format!(
+            r#"{{"items":[{{"kind":"cashaddr","data":"{}"}}],"accounts":[{{"xpub":"xpub-garbage","path":"m/0","gap_limit":20}}]}}"#,
+            address.encode()
+        ),
+    )
+    .unwrap();
+
+    let (_handle, watcher, _ui_tx, _ui_rx) = setup();
+    let watcher = watcher
+        .with_watch_list(WatchList::open(&path).unwrap())
+        .unwrap();
+
+    assert!(watcher.filter_state().is_watched(&address.script_pubkey()));
+}
+
+#[test]
+fn restart_only_scans_new_blocks() {
+    let mut list = mock::watch_list("scanned-height");
+    list.insert(WatchItem {
+        kind: WatchKind::CashAddr,
+        data: mock::address(1).encode(),
+        label: None,
+        birthday: Some(2),
+    });
+    list.set_scanned_height(8);
+    list.save().unwrap();
+
+    let (handle, watcher, ui_tx, _ui_rx) = setup();
+    let list = WatchList::open(list.file_path()).unwrap();
+    let mut watcher = watcher.auto_load_filter(true).with_watch_list(list).unwrap();
+    for n in [1, 2] {
+        watcher
+            .handle_client_event(mock::peer_negotiated(mock::peer(n)), &ui_tx)
+            .unwrap();
+    }
+    handle.take_commands();
+
+    watcher
+        .handle_client_event(mock::ready(12), &ui_tx)
+        .unwrap();
+    assert!(matches!(
+        handle.take_commands().as_slice(),
+        [Command::MerkleBlockRescan {
+            from: Bound::Included(9),
+            to: Bound::Included(12),
+            ..
+        }]
+    ));
+
+    // An item born before the scanned height is scanned for from its birthday,
+    // by the idle peer.
+    watcher
+        .handle_user_input(
+            UIMessage::AddWatchItem {
+                data: mock::address(2).encode(),
+                label: None,
+                birthday: Some(5),
+            },
+            &ui_tx,
+        )
+        .unwrap();
+    assert!(matches!(
+        handle.take_commands().as_slice(),
+        [
+            Command::LoadBloomFilter(_),
+            Command::MerkleBlockRescan {
+                from: Bound::Included(5),
+                to: Bound::Included(8),
+                peers,
+            }
+        ] if peers == &vec![mock::peer(2)]
+    ));
+    assert_eq!(watcher.watch_list().unwrap().scan_start(), Some(5));
+}
+
+#[test]
+fn rescan_is_split_across_filtered_peers() {
+    let (handle, watcher, ui_tx, ui_rx) = setup();
+    let mut watcher = watcher.scan_chunk_size(2);
+    let blocks = mine(&handle, 6);
+    load_filter_onto(&mut watcher, &ui_tx, &[1, 2]);
+    handle.take_commands();
+
+    watcher
+        .handle_user_input(UIMessage::RequestBlocks(MerkleScanRange::new(1, 6)), &ui_tx)
+        .unwrap();
+    assert_eq!(
+        rescans(&handle),
+        vec![(1, 2, vec![mock::peer(1)]), (3, 4, vec![mock::peer(2)])]
+    );
+
+    // Peer 1 is done with its chunk and gets the last one.
+    for height in 1..=2 {
+        receive(&mut watcher, &ui_tx, 1, &blocks, height);
+    }
+    assert_eq!(rescans(&handle), vec![(5, 6, vec![mock::peer(1)])]);
+
+    // Peer 2 goes away mid-chunk, its chunk waits for peer 1.
+    receive(&mut watcher, &ui_tx, 2, &blocks, 3);
+    watcher
+        .handle_client_event(mock::peer_disconnected(mock::peer(2)), &ui_tx)
+        .unwrap();
+    assert!(rescans(&handle).is_empty());
+    for height in 5..=6 {
+        receive(&mut watcher, &ui_tx, 1, &blocks, height);
+    }
+    assert_eq!(rescans(&handle), vec![(3, 4, vec![mock::peer(1)])]);
+
+    for height in 3..=4 {
+        receive(&mut watcher, &ui_tx, 1, &blocks, height);
+    }
+    assert!(watcher.scan().is_none());
+    let progress = mock::drain(&ui_rx)
+        .into_iter()
+        .filter_map(|m| match m {
+            UIMessage::ScanProgress(p) => Some(p),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(progress.len() > 1);
+    assert!(progress.last().is_some_and(|p| p.is_done() && p.total == 6));
+    let last = progress.last().unwrap();
+    assert!(last.covers(&MerkleScanRange::new(1, 6)));
+    assert!(!last.covers(&MerkleScanRange::new(1, 7)));
+}
+
+#[test]
+fn rescan_resumes_after_restart() {
+    let list = mock::watch_list("resumed-scan");
+    let path = list.file_path().to_owned();
+    let (handle, watcher, ui_tx, _ui_rx) = setup();
+    let mut watcher = watcher.scan_chunk_size(2).with_watch_list(list).unwrap();
+    let blocks = mine(&handle, 4);
+    load_filter_onto(&mut watcher, &ui_tx, &[1]);
+    handle.take_commands();
+
+    watcher
+        .handle_user_input(UIMessage::RequestBlocks(MerkleScanRange::new(1, 4)), &ui_tx)
+        .unwrap();
+    for height in 1..=3 {
+        receive(&mut watcher, &ui_tx, 1, &blocks, height);
+    }
+    assert_eq!(
+        rescans(&handle),
+        vec![(1, 2, vec![mock::peer(1)]), (3, 4, vec![mock::peer(1)])]
+    );
+    drop(watcher);
+
+    // Only the chunk left unfinished is scanned again.
+    let list = WatchList::open(&path).unwrap();
+    let mut watcher = Watcher::new(handle.clone(), Network::Chipnet)
+        .scan_chunk_size(2)
+        .with_watch_list(list)
+        .unwrap();
+    load_filter_onto(&mut watcher, &ui_tx, &[2]);
+    handle.take_commands();
+
+    watcher.handle_tick(Instant::now(), &ui_tx).unwrap();
+    assert_eq!(rescans(&handle), vec![(3, 4, vec![mock::peer(2)])]);
+}
+
+#[test]
+fn stalled_chunk_is_scanned_again() {
+    let (handle, watcher, ui_tx, _ui_rx) = setup();
+    let mut watcher = watcher.scan_chunk_size(2);
+    let blocks = mine(&handle, 4);
+    load_filter_onto(&mut watcher, &ui_tx, &[1]);
+    handle.take_commands();
+
+    watcher
+        .handle_user_input(UIMessage::RequestBlocks(MerkleScanRange::new(1, 4)), &ui_tx)
+        .unwrap();
+    receive(&mut watcher, &ui_tx, 1, &blocks, 1);
+    watcher.handle_tick(Instant::now(), &ui_tx).unwrap();
+    assert_eq!(rescans(&handle), vec![(1, 2, vec![mock::peer(1)])]);
+
+    watcher
+        .handle_tick(Instant::now() + scan::STALL_TIMEOUT, &ui_tx)
+        .unwrap();
+    assert_eq!(rescans(&handle), vec![(1, 2, vec![mock::peer(1)])]);
+}
+
+#[test]
+fn rescan_completes_once_its_matched_txs_are_applied() {
+    let (handle, mut watcher, ui_tx, ui_rx) = setup();
+    let address = mock::address(1);
+    load_filter_onto(&mut watcher, &ui_tx, &[1]);
+    let tx = mock::transaction(mock::outpoint(1), &[(address.script_pubkey(), 10_000)]);
+    let (block, height) = handle.mine(vec![tx.clone()]);
+    let is_done = |m: &UIMessage| matches!(m, UIMessage::ScanProgress(p) if p.is_done());
+
+    // Asking again for heights already being scanned still gets an answer.
+    for _ in 0..2 {
+        watcher
+            .handle_user_input(
+                UIMessage::RequestBlocks(MerkleScanRange::new(height, height)),
+                &ui_tx,
+            )
+            .unwrap();
+    }
+    let progress = mock::drain(&ui_rx)
+        .into_iter()
+        .filter(|m| matches!(m, UIMessage::ScanProgress(_)))
+        .collect::<Vec<_>>();
+    assert_eq!(progress.len(), 2);
+    assert!(!progress.iter().any(is_done));
+
+    watcher
+        .handle_client_event(
+            mock::merkle_block_received(mock::peer(1), mock::merkle_block(&block, &[tx.txid()]), height),
+            &ui_tx,
+        )
+        .unwrap();
+    assert!(!mock::drain(&ui_rx).iter().any(is_done));
+
+    watcher
+        .handle_client_event(mock::matched_tx(tx), &ui_tx)
+        .unwrap();
+    let msgs = mock::drain(&ui_rx);
+    let balance = msgs
+        .iter()
+        .position(|m| matches!(m, UIMessage::BalanceUpdated { .. }))
+        .unwrap();
+    let done = msgs.iter().position(is_done).unwrap();
+    assert!(balance < done, "the payment is applied before the scan is done");
+    assert!(watcher.scan().is_none());
+}